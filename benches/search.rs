@@ -0,0 +1,57 @@
+// Compares the line by line search functions against the memchr based LiteralSearcher.
+// Run with `cargo bench`, criterion puts html reports in target/criterion/
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rust_scratch_pad::literal::LiteralSearcher;
+use rust_scratch_pad::{search, search_case_insensitive};
+
+const WORDS: &[&str] = &[
+    "the", "quick", "brown", "fox", "jumps", "over", "lazy", "dog", "fn", "let", "mut",
+    "impl", "struct", "return", "error", "warning", "request", "handler", "=", "{", "}",
+];
+
+// Lines of random words, seeded so every run searches the same corpus
+fn corpus(size: usize) -> String {
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut contents = String::with_capacity(size + 128);
+    while contents.len() < size {
+        for _ in 0..rng.gen_range(0..16) {
+            contents.push_str(WORDS[rng.gen_range(0..WORDS.len())]);
+            contents.push(' ');
+        }
+        contents.push('\n');
+    }
+    contents
+}
+
+fn bench_search(c: &mut Criterion) {
+    for size in [1 << 20, 16 << 20] {
+        let contents = corpus(size);
+        // "zebra" never occurs, "error" is on a good chunk of the lines
+        for query in ["zebra", "error"] {
+            let mut group = c.benchmark_group(format!("{}MiB/{}", size >> 20, query));
+            group.throughput(Throughput::Bytes(contents.len() as u64));
+            group.sample_size(20);
+
+            group.bench_function(BenchmarkId::new("case_sensitive", "lines"), |b| {
+                b.iter(|| search(black_box(query), black_box(&contents)))
+            });
+            let searcher = LiteralSearcher::new(query, false);
+            group.bench_function(BenchmarkId::new("case_sensitive", "literal"), |b| {
+                b.iter(|| searcher.find_lines(black_box(&contents)))
+            });
+
+            group.bench_function(BenchmarkId::new("case_insensitive", "lines"), |b| {
+                b.iter(|| search_case_insensitive(black_box(query), black_box(&contents)))
+            });
+            let searcher = LiteralSearcher::new(query, true);
+            group.bench_function(BenchmarkId::new("case_insensitive", "literal"), |b| {
+                b.iter(|| searcher.find_lines(black_box(&contents)))
+            });
+            group.finish();
+        }
+    }
+}
+
+criterion_group!(benches, bench_search);
+criterion_main!(benches);
@@ -0,0 +1,321 @@
+use std::io::{self, Read, Write};
+use std::mem;
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::http::{Body, Request, Response};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// The token used in `Accept-Encoding` and `Content-Encoding`
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    pub fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = self.encoder();
+        encoder.writer().write_all(data)?;
+        encoder.finish()
+    }
+
+    fn encoder(self) -> Encoder {
+        match self {
+            // quality 5 is about as fast as gzip while still compressing better
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default())),
+            // "deflate" in http means the zlib format, not a raw deflate stream
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), flate2::Compression::default())),
+        }
+    }
+}
+
+// Any of the encoders, writing into memory
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Brotli(encoder) => encoder.as_mut(),
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Deflate(encoder) => encoder,
+        }
+    }
+
+    // what has been encoded so far
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Encoder::Brotli(encoder) => encoder.get_mut(),
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+            Encoder::Deflate(encoder) => encoder.get_mut(),
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(mut encoder) => {
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            },
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+// Encodes a streamed body as it is read, so it never has to be in memory whole
+struct EncodingReader {
+    source: Box<dyn Read + Send>,
+    // None once the source has run out and the encoding is finished
+    encoder: Option<Encoder>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    // how much of output has been read already
+    position: usize,
+}
+
+impl EncodingReader {
+    fn new(source: Box<dyn Read + Send>, encoding: Encoding) -> EncodingReader {
+        EncodingReader { source, encoder: Some(encoding.encoder()), input: vec![0; 16 * 1024], output: Vec::new(), position: 0 }
+    }
+}
+
+impl Read for EncodingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() {
+            let Some(encoder) = self.encoder.as_mut() else { return Ok(0) };
+            let read = match self.source.read(&mut self.input) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.output.clear();
+            self.position = 0;
+            if read == 0 {
+                self.output = self.encoder.take().map_or(Ok(Vec::new()), Encoder::finish)?;
+            } else {
+                encoder.writer().write_all(&self.input[..read])?;
+                // a generated body may take a while between pieces, so each goes out as it comes
+                encoder.writer().flush()?;
+                // swapped rather than copied, the encoder gets the emptied buffer to reuse
+                mem::swap(&mut self.output, encoder.output());
+            }
+        }
+        let available = &self.output[self.position..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Picks the encoding the client likes best from an `Accept-Encoding` header, preferring
+/// brotli, then gzip, then deflate when it likes several equally. None means identity.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let accept_encoding = accept_encoding?;
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+        let quality = quality(accept_encoding, encoding.name());
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+// The q value of the token, or of *, 0 if neither is listed
+fn quality(accept_encoding: &str, token: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(token) {
+            return quality;
+        }
+        if name == "*" {
+            wildcard = quality;
+        }
+    }
+    wildcard
+}
+
+/// Whether compressing this kind of content is worth it, images and archives mostly are
+/// compressed already
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "application/wasm"
+        )
+}
+
+/// Compresses response bodies for clients which accept it
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// Smaller bodies are sent as they are, the headers would eat up most of the savings
+    pub min_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression { min_size: 1024 }
+    }
+}
+
+impl Compression {
+    pub fn apply(&self, request: &Request, mut response: Response) -> Response {
+        let big_enough = match &response.body {
+            Body::Full(body) => body.len() >= self.min_size,
+            // encoded as it is sent, unless it is known to be too small to bother
+            Body::Stream { length, .. } => length.is_none_or(|length| length >= self.min_size as u64),
+            Body::Omitted { .. } => false,
+        };
+        // a range is of the identity bytes, it cant be encoded on its own
+        let compressible = big_enough
+            && response.status != 206
+            && response.header("content-encoding").is_none()
+            && response.header("content-type").is_some_and(is_compressible);
+        if !compressible {
+            return response;
+        }
+        // caches have to keep the encodings apart, even when this client got the plain one
+        response.add_vary("Accept-Encoding");
+        let Some(encoding) = negotiate(request.header("accept-encoding")) else {
+            return response;
+        };
+        response.body = match mem::replace(&mut response.body, Body::Full(Vec::new())) {
+            Body::Full(body) => match encoding.encode(&body) {
+                Ok(encoded) => Body::Full(encoded),
+                Err(e) => {
+                    warn!("Failed to {} encode response: {}", encoding.name(), e);
+                    response.body = Body::Full(body);
+                    return response;
+                },
+            },
+            // the encoded length isnt known until the end, so it goes out chunked
+            Body::Stream { reader, .. } => Body::Stream { reader: Box::new(EncodingReader::new(reader, encoding)), length: None },
+            omitted => omitted,
+        };
+        response.set_header("Content-Encoding", encoding.name());
+        // the bytes differ from the identity response, so a strong tag would be a lie
+        if let Some(etag) = response.header("etag").filter(|etag| !etag.starts_with("W/")) {
+            let weak = format!("W/{}", etag);
+            response.set_header("ETag", &weak);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn request(accept_encoding: &str) -> Request {
+        let mut request = Request::default();
+        request.headers.insert("accept-encoding".to_string(), accept_encoding.to_string());
+        request
+    }
+
+    #[test]
+    fn negotiates_by_quality_then_preference() {
+        assert_eq!(Some(Encoding::Brotli), negotiate(Some("gzip, deflate, br")));
+        assert_eq!(Some(Encoding::Gzip), negotiate(Some("gzip;q=1.0, br;q=0.5")));
+        assert_eq!(Some(Encoding::Deflate), negotiate(Some("deflate")));
+        assert_eq!(Some(Encoding::Gzip), negotiate(Some("br;q=0, *")));
+        assert_eq!(None, negotiate(Some("identity")));
+        assert_eq!(None, negotiate(Some("gzip;q=0")));
+        assert_eq!(None, negotiate(None));
+    }
+
+    #[test]
+    fn compresses_text_above_the_threshold() {
+        let body = "hello ".repeat(1000);
+        let response = Response::text(200, body.clone()).with_header("ETag", "\"1-2\"");
+        let response = Compression::default().apply(&request("gzip"), response);
+        assert_eq!(Some("gzip"), response.header("content-encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("vary"));
+        assert_eq!(Some("W/\"1-2\""), response.header("etag"));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(response.body.as_bytes().unwrap()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(body, decoded);
+
+        let response = Compression::default().apply(&request("br"), Response::text(200, body.clone()));
+        let mut decoded = String::new();
+        brotli::Decompressor::new(response.body.as_bytes().unwrap(), 4096).read_to_string(&mut decoded).unwrap();
+        assert_eq!(body, decoded);
+    }
+
+    fn decode(encoding: &str, body: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        match encoding {
+            "gzip" => flate2::read::GzDecoder::new(body).read_to_end(&mut decoded),
+            "deflate" => flate2::read::ZlibDecoder::new(body).read_to_end(&mut decoded),
+            _ => brotli::Decompressor::new(body, 4096).read_to_end(&mut decoded),
+        }
+        .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn compresses_streamed_bodies_as_they_are_sent() {
+        let text = (0..100).map(|i| format!("line {}\n", i).repeat(50)).collect::<String>().into_bytes();
+        let streamed = || {
+            let chunks: Vec<Vec<u8>> = text.chunks(5000).map(|chunk| chunk.to_vec()).collect();
+            Response::new(200).with_header("Content-Type", "text/plain").with_chunks(chunks)
+        };
+        for encoding in ["gzip", "br", "deflate"] {
+            let response = Compression::default().apply(&request(encoding), streamed());
+            assert_eq!(Some(encoding), response.header("content-encoding"));
+            assert_eq!(None, response.body.len());
+            let encoded = response.body.into_bytes().unwrap();
+            assert!(encoded.len() < text.len() / 4);
+            assert_eq!(text, decode(encoding, &encoded));
+        }
+
+        // a known small length isnt worth it, a range cant be encoded on its own
+        let small = Response::text(200, "").with_reader(&b"tiny"[..], Some(4));
+        assert_eq!(None, Compression::default().apply(&request("gzip"), small).header("content-encoding"));
+        let mut partial = streamed();
+        partial.status = 206;
+        assert_eq!(None, Compression::default().apply(&request("gzip"), partial).header("content-encoding"));
+    }
+
+    #[test]
+    fn leaves_small_and_binary_bodies_alone() {
+        let small = Compression::default().apply(&request("gzip"), Response::text(200, "hi"));
+        assert_eq!((None, None), (small.header("content-encoding"), small.header("vary")));
+
+        let image = Response::new(200).with_header("Content-Type", "image/png").with_body(vec![0; 4096]);
+        let image = Compression::default().apply(&request("gzip"), image);
+        assert_eq!(None, image.header("content-encoding"));
+    }
+
+    #[test]
+    fn varies_even_when_sent_uncompressed() {
+        let response = Response::text(200, "a".repeat(2048)).with_header("Vary", "Origin");
+        let response = Compression::default().apply(&Request::default(), response);
+        assert_eq!(None, response.header("content-encoding"));
+        assert_eq!(Some("Origin, Accept-Encoding"), response.header("vary"));
+    }
+}
@@ -0,0 +1,356 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::compression::Compression;
+use crate::log::{AccessLogFormat, Level};
+use crate::proxy;
+use crate::server::ConnectionOptions;
+
+pub const USAGE: &str = "\
+Usage: my_web_server [OPTIONS]
+  --address ADDR         address to listen on (default localhost)
+  --port PORT            port to listen on (default 7878)
+  --workers N            worker threads in the pool, or tokio's worker threads (default 3)
+  --runtime RUNTIME      threads (a blocking thread per connection) or tokio (default threads)
+  --max-connections N    exit after accepting N connections (default: serve forever)
+  --idle-timeout SECS    close keep-alive connections idle for this long (default 5)
+  --max-requests N       close a connection after N requests (default 100)
+  --request-timeout SECS answer 408 when a request takes longer to arrive (default 30)
+  --write-timeout SECS   drop clients which dont read their response for this long (default 30)
+  --max-header-size BYTES  answer 431 above this many header bytes (default 16384)
+  --max-body-size BYTES  answer 413 to bigger request bodies (default 10485760)
+  --shutdown-timeout SECS  on SIGINT/SIGTERM wait this long for requests in flight (default 10)
+  --document-root DIR    directory served under /static/ (default $DOCUMENT_ROOT or ./public)
+  --templates DIR        directory the html templates are loaded from (default ./templates)
+  --access-log FORMAT    common, combined, json or off (default common)
+  --access-log-file PATH append the access log to a file instead of stdout
+  --log-level LEVEL      error, warn, info or debug diagnostics on stderr (default info)
+  --tls-cert PATH        serve HTTPS with this PEM certificate chain, needs --tls-key
+  --tls-key PATH         PEM private key for --tls-cert
+  --redirect-port PORT   with TLS, also listen for plain HTTP here and redirect it to https://ADDR
+  --public-host NAME     host name clients reach the server by, redirects go there instead of ADDR,
+                         needed with --redirect-port when ADDR is 0.0.0.0 or ::
+  --compression-min-size BYTES  compress text responses at least this big (default 1024)
+  --no-compression       always send responses uncompressed
+  --no-metrics           dont count requests or serve them on /metrics
+  --proxy PREFIX=UPSTREAMS  forward requests under PREFIX to comma separated HOST:PORTs in turn,
+                         can be given more than once, e.g. --proxy /api=localhost:9000,localhost:9001
+  --proxy-timeout SECS   answer 504 when an upstream is silent for this long (default 30)
+  --health-check PATH    path upstreams are checked on every 10 seconds (default /)";
+
+/// How connections are served
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runtime {
+    /// Each connection gets a worker from the [`ThreadPool`](crate::ThreadPool) for as long
+    /// as it is open
+    Threads,
+    /// Connections are tokio tasks and only handlers get a thread, see
+    /// [`tokio_server`](crate::tokio_server)
+    Tokio,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+    pub workers: usize,
+    pub runtime: Runtime,
+    /// Stop accepting after this many connections, None serves until the process is stopped
+    pub max_connections: Option<usize>,
+    pub connection: ConnectionOptions,
+    pub document_root: PathBuf,
+    pub templates: PathBuf,
+    /// How long requests in flight get to finish once shutting down
+    pub shutdown_timeout: Duration,
+    /// None turns the access log off
+    pub access_log_format: Option<AccessLogFormat>,
+    /// Where the access log goes, stdout when None
+    pub access_log_file: Option<PathBuf>,
+    pub log_level: Level,
+    /// PEM certificate chain and private key, serves HTTPS when both are set
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Plaintext port which redirects everything to the HTTPS one
+    pub redirect_port: Option<u16>,
+    /// Where clients reach the server, for redirects. The address it listens on otherwise.
+    pub public_host: Option<String>,
+    /// Count requests and serve the counts on /metrics
+    pub metrics: bool,
+    /// Path prefixes without the trailing slash, each with the `host:port`s it is forwarded to
+    pub proxies: Vec<(String, Vec<String>)>,
+    pub proxy_timeout: Duration,
+    /// Requested from every upstream to see whether it is up, anything below 500 counts
+    pub health_check: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: "localhost".to_string(),
+            port: 7878,
+            workers: 3,
+            runtime: Runtime::Threads,
+            max_connections: None,
+            connection: ConnectionOptions::default(),
+            document_root: PathBuf::from(std::env::var("DOCUMENT_ROOT").unwrap_or_else(|_| "public".to_string())),
+            templates: PathBuf::from("templates"),
+            shutdown_timeout: Duration::from_secs(10),
+            access_log_format: Some(AccessLogFormat::Common),
+            access_log_file: None,
+            log_level: Level::Info,
+            tls_cert: None,
+            tls_key: None,
+            redirect_port: None,
+            public_host: None,
+            metrics: true,
+            proxies: Vec::new(),
+            proxy_timeout: Duration::from_secs(30),
+            health_check: "/".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn build(mut args: impl Iterator<Item=String>) -> Result<Config, &'static str> {
+        args.next();
+        let mut config = Config::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--address" => config.address = args.next().ok_or("--address expects an address")?,
+                "--port" => config.port = parse_next(&mut args, "--port expects a port number")?,
+                "--workers" => {
+                    config.workers = parse_next(&mut args, "--workers expects a number")?;
+                    if config.workers == 0 {
+                        return Err("--workers must be at least 1");
+                    }
+                },
+                "--max-connections" => {
+                    config.max_connections = Some(parse_next(&mut args, "--max-connections expects a number")?)
+                },
+                "--idle-timeout" => {
+                    let secs = parse_next(&mut args, "--idle-timeout expects a number of seconds")?;
+                    if secs == 0 {
+                        return Err("--idle-timeout must be at least 1 second");
+                    }
+                    config.connection.idle_timeout = Duration::from_secs(secs);
+                },
+                "--max-requests" => {
+                    config.connection.max_requests = parse_next(&mut args, "--max-requests expects a number")?
+                },
+                "--request-timeout" => {
+                    let secs = parse_next(&mut args, "--request-timeout expects a number of seconds")?;
+                    if secs == 0 {
+                        return Err("--request-timeout must be at least 1 second");
+                    }
+                    config.connection.request_timeout = Duration::from_secs(secs);
+                },
+                "--write-timeout" => {
+                    let secs = parse_next(&mut args, "--write-timeout expects a number of seconds")?;
+                    if secs == 0 {
+                        return Err("--write-timeout must be at least 1 second");
+                    }
+                    config.connection.write_timeout = Duration::from_secs(secs);
+                },
+                "--max-header-size" => {
+                    config.connection.limits.max_header_bytes = parse_next(&mut args, "--max-header-size expects a number of bytes")?
+                },
+                "--max-body-size" => {
+                    config.connection.limits.max_body = parse_next(&mut args, "--max-body-size expects a number of bytes")?
+                },
+                "--shutdown-timeout" => {
+                    let secs = parse_next(&mut args, "--shutdown-timeout expects a number of seconds")?;
+                    config.shutdown_timeout = Duration::from_secs(secs);
+                },
+                "--document-root" => {
+                    config.document_root = PathBuf::from(args.next().ok_or("--document-root expects a directory")?)
+                },
+                "--templates" => {
+                    config.templates = PathBuf::from(args.next().ok_or("--templates expects a directory")?)
+                },
+                "--access-log" => {
+                    let format = args.next().ok_or("--access-log expects a format")?;
+                    config.access_log_format = match format.as_str() {
+                        "off" => None,
+                        _ => Some(AccessLogFormat::parse(&format).ok_or("--access-log expects common, combined, json or off")?),
+                    };
+                },
+                "--access-log-file" => {
+                    config.access_log_file = Some(PathBuf::from(args.next().ok_or("--access-log-file expects a path")?))
+                },
+                "--log-level" => {
+                    config.log_level = args.next().and_then(|level| Level::parse(&level))
+                        .ok_or("--log-level expects error, warn, info or debug")?
+                },
+                "--tls-cert" => config.tls_cert = Some(PathBuf::from(args.next().ok_or("--tls-cert expects a path")?)),
+                "--tls-key" => config.tls_key = Some(PathBuf::from(args.next().ok_or("--tls-key expects a path")?)),
+                "--redirect-port" => {
+                    config.redirect_port = Some(parse_next(&mut args, "--redirect-port expects a port number")?)
+                },
+                "--public-host" => config.public_host = Some(args.next().ok_or("--public-host expects a host name")?),
+                "--compression-min-size" => {
+                    let min_size = parse_next(&mut args, "--compression-min-size expects a number of bytes")?;
+                    config.connection.compression = Some(Compression { min_size });
+                },
+                "--no-compression" => config.connection.compression = None,
+                "--no-metrics" => config.metrics = false,
+                "--runtime" => {
+                    config.runtime = match args.next().as_deref() {
+                        Some("threads") => Runtime::Threads,
+                        Some("tokio") => Runtime::Tokio,
+                        _ => return Err("--runtime expects threads or tokio"),
+                    };
+                },
+                "--proxy" => {
+                    let spec = args.next().ok_or("--proxy expects PREFIX=HOST:PORT[,HOST:PORT...]")?;
+                    let (prefix, upstreams) = spec.split_once('=').ok_or("--proxy expects PREFIX=HOST:PORT[,HOST:PORT...]")?;
+                    if !prefix.starts_with('/') {
+                        return Err("--proxy prefixes start with a /");
+                    }
+                    let upstreams = upstreams.split(',').map(proxy::upstream_address).collect::<Option<Vec<_>>>()
+                        .ok_or("--proxy upstreams are HOST:PORT or http://HOST:PORT")?;
+                    config.proxies.push((prefix.trim_end_matches('/').to_string(), upstreams));
+                },
+                "--proxy-timeout" => {
+                    let secs = parse_next(&mut args, "--proxy-timeout expects a number of seconds")?;
+                    if secs == 0 {
+                        return Err("--proxy-timeout must be at least 1 second");
+                    }
+                    config.proxy_timeout = Duration::from_secs(secs);
+                },
+                "--health-check" => {
+                    config.health_check = args.next().filter(|path| path.starts_with('/')).ok_or("--health-check expects a path")?
+                },
+                _ => return Err("unknown argument"),
+            }
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err("--tls-cert and --tls-key go together");
+        }
+        if config.redirect_port.is_some() && config.tls_cert.is_none() {
+            return Err("--redirect-port needs --tls-cert and --tls-key");
+        }
+        // nobody can be sent to https://0.0.0.0
+        let wildcard = matches!(config.address.as_str(), "0.0.0.0" | "::" | "[::]");
+        if config.redirect_port.is_some() && wildcard && config.public_host.is_none() {
+            return Err("--redirect-port on a wildcard --address needs --public-host");
+        }
+        if config.runtime == Runtime::Tokio && config.tls_cert.is_some() {
+            return Err("--runtime tokio doesnt support TLS yet");
+        }
+        Ok(config)
+    }
+}
+
+fn parse_next<T: std::str::FromStr>(
+    args: &mut impl Iterator<Item=String>,
+    error: &'static str,
+) -> Result<T, &'static str> {
+    args.next().and_then(|arg| arg.parse().ok()).ok_or(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(args: &[&str]) -> Result<Config, &'static str> {
+        Config::build(std::iter::once("my_web_server").chain(args.iter().copied()).map(String::from))
+    }
+
+    #[test]
+    fn defaults_serve_forever() {
+        let config = build(&[]).unwrap();
+        assert_eq!(("localhost", 7878, 3), (config.address.as_str(), config.port, config.workers));
+        assert_eq!(None, config.max_connections);
+    }
+
+    #[test]
+    fn parses_flags() {
+        let config = build(&["--address", "0.0.0.0", "--port", "8080", "--workers", "8", "--max-connections", "2", "--idle-timeout", "30"]).unwrap();
+        assert_eq!(("0.0.0.0", 8080, 8), (config.address.as_str(), config.port, config.workers));
+        assert_eq!(Some(2), config.max_connections);
+        assert_eq!(Duration::from_secs(30), config.connection.idle_timeout);
+        assert_eq!(PathBuf::from("views"), build(&["--templates", "views"]).unwrap().templates);
+    }
+
+    #[test]
+    fn parses_logging_flags() {
+        let config = build(&["--access-log", "json", "--access-log-file", "access.log", "--log-level", "debug"]).unwrap();
+        assert_eq!(Some(AccessLogFormat::Json), config.access_log_format);
+        assert_eq!(Some(PathBuf::from("access.log")), config.access_log_file);
+        assert_eq!(Level::Debug, config.log_level);
+        assert_eq!(None, build(&["--access-log", "off"]).unwrap().access_log_format);
+        assert!(build(&["--access-log", "apache"]).is_err());
+        assert!(build(&["--log-level", "loud"]).is_err());
+    }
+
+    #[test]
+    fn tls_needs_cert_and_key() {
+        let config = build(&["--tls-cert", "cert.pem", "--tls-key", "key.pem", "--redirect-port", "8080"]).unwrap();
+        assert_eq!(Some(PathBuf::from("key.pem")), config.tls_key);
+        assert_eq!(Some(8080), config.redirect_port);
+        assert!(build(&["--tls-cert", "cert.pem"]).is_err());
+        assert!(build(&["--redirect-port", "8080"]).is_err());
+    }
+
+    #[test]
+    fn redirects_on_a_wildcard_address_need_a_public_host() {
+        let tls = ["--tls-cert", "cert.pem", "--tls-key", "key.pem", "--redirect-port", "8080", "--address", "0.0.0.0"];
+        assert!(build(&tls).is_err());
+        let config = build(&[&tls[..], &["--public-host", "example.com"]].concat()).unwrap();
+        assert_eq!(Some("example.com"), config.public_host.as_deref());
+    }
+
+    #[test]
+    fn compression_flags() {
+        assert_eq!(1024, build(&[]).unwrap().connection.compression.unwrap().min_size);
+        assert_eq!(10, build(&["--compression-min-size", "10"]).unwrap().connection.compression.unwrap().min_size);
+        assert!(build(&["--no-compression"]).unwrap().connection.compression.is_none());
+    }
+
+    #[test]
+    fn metrics_can_be_turned_off() {
+        assert!(build(&[]).unwrap().metrics);
+        assert!(!build(&["--no-metrics"]).unwrap().metrics);
+    }
+
+    #[test]
+    fn picks_a_runtime() {
+        assert_eq!(Runtime::Threads, build(&[]).unwrap().runtime);
+        assert_eq!(Runtime::Tokio, build(&["--runtime", "tokio"]).unwrap().runtime);
+        assert!(build(&["--runtime", "async-std"]).is_err());
+        assert!(build(&["--runtime", "tokio", "--tls-cert", "cert.pem", "--tls-key", "key.pem"]).is_err());
+    }
+
+    #[test]
+    fn parses_proxies() {
+        let config = build(&["--proxy", "/api/=localhost:9000,http://10.0.0.2", "--proxy", "/auth=auth:80", "--proxy-timeout", "5"]).unwrap();
+        assert_eq!(
+            vec![
+                ("/api".to_string(), vec!["localhost:9000".to_string(), "10.0.0.2:80".to_string()]),
+                ("/auth".to_string(), vec!["auth:80".to_string()]),
+            ],
+            config.proxies
+        );
+        assert_eq!(Duration::from_secs(5), config.proxy_timeout);
+        assert!(build(&["--proxy", "api=localhost:9000"]).is_err());
+        assert!(build(&["--proxy", "/api=https://localhost"]).is_err());
+        assert!(build(&["--proxy", "/api"]).is_err());
+    }
+
+    #[test]
+    fn parses_timeouts_and_limits() {
+        let config = build(&["--request-timeout", "3", "--write-timeout", "4", "--max-header-size", "100", "--max-body-size", "0"]).unwrap();
+        assert_eq!(Duration::from_secs(3), config.connection.request_timeout);
+        assert_eq!(Duration::from_secs(4), config.connection.write_timeout);
+        assert_eq!((100, 0), (config.connection.limits.max_header_bytes, config.connection.limits.max_body));
+        assert!(build(&["--request-timeout", "0"]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert!(build(&["--port", "70000"]).is_err());
+        assert!(build(&["--workers", "0"]).is_err());
+        assert!(build(&["--workers"]).is_err());
+        assert!(build(&["--verbose"]).is_err());
+    }
+}
@@ -0,0 +1,571 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::de::DeserializeOwned;
+
+use crate::http::{percent_decode, Request, Response};
+
+/// Multipart fields bigger than this are rejected, they are kept in memory. Files are written
+/// to disk as they are parsed instead, but the body they come from is only read off the
+/// connection as it goes for routes added with [`Router::streaming`](crate::router::Router::streaming).
+/// Everywhere else it was already read into memory whole, up to the body size limit.
+pub const MAX_FIELD_SIZE: usize = 64 * 1024;
+/// More parts than this in a multipart body are rejected
+pub const MAX_PARTS: usize = 100;
+// for the headers of one multipart part
+const MAX_PART_HEADERS: usize = 8 * 1024;
+
+// Typed access to what the client sent, anything malformed comes back as an ExtractError
+// which turns into a 400 response
+impl Request {
+    /// The query string, e.g. `?q=rust&page=2`, as a struct or a `HashMap<String, String>`.
+    /// Like the other extractors it fails with an [`ExtractError`], which turns into a response.
+    ///
+    /// ```no_run
+    /// use my_web_server::http::Response;
+    /// use my_web_server::router::Router;
+    /// use serde::Deserialize;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Search {
+    ///     q: String,
+    ///     page: Option<u32>,
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.get("/search", |request| {
+    ///     let search: Search = match request.query() {
+    ///         Ok(search) => search,
+    ///         Err(e) => return e.into(),
+    ///     };
+    ///     Response::text(200, format!("{} on page {}", search.q, search.page.unwrap_or(1)))
+    /// });
+    /// ```
+    pub fn query<T: DeserializeOwned>(&self) -> Result<T, ExtractError> {
+        let query = self.query.as_deref().unwrap_or("");
+        check_urlencoded(query)?;
+        serde_urlencoded::from_str(query).map_err(|e| ExtractError::Malformed(format!("invalid query string: {}", e)))
+    }
+
+    /// An `application/x-www-form-urlencoded` body
+    pub fn form<T: DeserializeOwned>(&self) -> Result<T, ExtractError> {
+        self.expect_content_type("application/x-www-form-urlencoded")?;
+        let body = std::str::from_utf8(&self.body)
+            .map_err(|_| ExtractError::Malformed("form body is not utf-8".to_string()))?;
+        check_urlencoded(body)?;
+        serde_urlencoded::from_str(body).map_err(|e| ExtractError::Malformed(format!("invalid form: {}", e)))
+    }
+
+    /// An `application/json` body, `+json` types like `application/merge-patch+json` count too
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, ExtractError> {
+        let is_json = self.media_type().is_some_and(|media_type| {
+            media_type == "application/json" || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+        });
+        if !is_json {
+            return Err(ExtractError::UnsupportedMediaType("application/json"));
+        }
+        serde_json::from_slice(&self.body).map_err(|e| ExtractError::Malformed(format!("invalid json: {}", e)))
+    }
+
+    /// A `multipart/form-data` body. Uploaded files are written to `upload_dir` as they are
+    /// parsed and removed again when the [`UploadedFile`] is dropped, unless it is persisted.
+    ///
+    /// Uploads bigger than the body size limit need a route added with
+    /// [`Router::streaming`](crate::router::Router::streaming), this then reads the body
+    /// straight off the connection.
+    pub fn multipart(&self, upload_dir: &Path) -> Result<Multipart, ExtractError> {
+        self.expect_content_type("multipart/form-data")?;
+        let content_type = self.header("content-type").unwrap_or("");
+        let boundary = parameter(content_type, "boundary")
+            .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+            .ok_or_else(|| ExtractError::Malformed("multipart body without a boundary".to_string()))?;
+        parse_multipart(self.body_reader(), &boundary, upload_dir)
+    }
+
+    // the content type without its parameters, lowercased
+    fn media_type(&self) -> Option<String> {
+        let content_type = self.header("content-type")?;
+        Some(content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+    }
+
+    fn expect_content_type(&self, expected: &'static str) -> Result<(), ExtractError> {
+        match self.media_type() {
+            Some(media_type) if media_type == expected => Ok(()),
+            _ => Err(ExtractError::UnsupportedMediaType(expected)),
+        }
+    }
+}
+
+// serde_urlencoded replaces bad escapes and invalid utf-8 instead of failing, that should be a 400
+fn check_urlencoded(input: &str) -> Result<(), ExtractError> {
+    for pair in input.split('&') {
+        for part in pair.splitn(2, '=') {
+            if percent_decode(&part.replace('+', " ")).is_none() {
+                return Err(ExtractError::Malformed(format!("invalid percent encoding in {:?}", pair)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// a parameter like `boundary=xyz` or `name="a b"` from a header value
+fn parameter(header: &str, name: &str) -> Option<String> {
+    header.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let value = value.trim();
+        match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(quoted) => Some(quoted.replace("\\\"", "\"").replace("\\\\", "\\")),
+            None => Some(value.to_string()),
+        }
+    })
+}
+
+#[derive(Debug)]
+pub enum ExtractError {
+    /// The body isnt of the expected type, answered with a 415
+    UnsupportedMediaType(&'static str),
+    /// Answered with a 400
+    Malformed(String),
+    /// Writing an upload to disk failed, answered with a 500
+    Io(io::Error),
+}
+
+impl ExtractError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ExtractError::UnsupportedMediaType(_) => 415,
+            ExtractError::Malformed(_) => 400,
+            ExtractError::Io(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::UnsupportedMediaType(expected) => write!(f, "expected a body of type {}", expected),
+            ExtractError::Malformed(reason) => f.write_str(reason),
+            ExtractError::Io(e) => write!(f, "failed to store upload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+impl From<io::Error> for ExtractError {
+    fn from(e: io::Error) -> ExtractError {
+        ExtractError::Io(e)
+    }
+}
+
+impl From<ExtractError> for Response {
+    fn from(e: ExtractError) -> Response {
+        match e {
+            ExtractError::Io(e) => {
+                error!("Failed to store upload: {}", e);
+                Response::text(500, "Internal Server Error")
+            },
+            e => Response::text(e.status(), e.to_string()),
+        }
+    }
+}
+
+/// The parts of a `multipart/form-data` body
+#[derive(Debug, Default)]
+pub struct Multipart {
+    /// Parts without a file name, in the order they were sent
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<UploadedFile>,
+}
+
+impl Multipart {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(field, _)| field == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.field == name)
+    }
+}
+
+/// A file from a multipart body, stored under a generated name. Removed again on drop
+/// unless it is moved somewhere with [`persist`](UploadedFile::persist).
+#[derive(Debug)]
+pub struct UploadedFile {
+    pub field: String,
+    /// As the client sent it, so not to be trusted as a path
+    pub file_name: String,
+    pub content_type: Option<String>,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl UploadedFile {
+    /// Moves the file to `to`, which has to be on the same file system as the upload directory
+    pub fn persist(mut self, to: &Path) -> io::Result<()> {
+        fs::rename(&self.path, to)?;
+        // nothing left to clean up
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn parse_multipart<R: Read>(reader: R, boundary: &str, upload_dir: &Path) -> Result<Multipart, ExtractError> {
+    // the first boundary doesnt have a line break in front of it, pretend it does
+    let mut reader = Delimited { reader, buffer: b"\r\n".to_vec(), eof: false, read_failed: false };
+    parse_parts(&mut reader, boundary, upload_dir).map_err(|e| match e {
+        // a streamed body the client cut short or garbled, only writing the files is on us
+        ExtractError::Io(e) if reader.read_failed => ExtractError::Malformed(format!("couldnt read the body: {}", e)),
+        e => e,
+    })
+}
+
+fn parse_parts<R: Read>(reader: &mut Delimited<R>, boundary: &str, upload_dir: &Path) -> Result<Multipart, ExtractError> {
+    let malformed = |reason: &str| ExtractError::Malformed(format!("invalid multipart body: {}", reason));
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    let mut multipart = Multipart::default();
+
+    // anything before the first boundary is ignored
+    if !reader.copy_until(&delimiter, &mut io::sink(), usize::MAX)? {
+        return Err(malformed("no boundary"));
+    }
+    loop {
+        let mut after = [0; 2];
+        if !reader.read_exact(&mut after)? {
+            return Err(malformed("ends without the closing boundary"));
+        }
+        match &after {
+            // the closing boundary, whatever comes after it is ignored too
+            b"--" => return Ok(multipart),
+            b"\r\n" => {},
+            _ => return Err(malformed("garbage after boundary")),
+        }
+        if multipart.fields.len() + multipart.files.len() >= MAX_PARTS {
+            return Err(malformed("too many parts"));
+        }
+
+        let mut headers = Vec::new();
+        if !reader.copy_until(b"\r\n\r\n", &mut headers, MAX_PART_HEADERS)? {
+            return Err(malformed("part headers too long or cut short"));
+        }
+        let headers = String::from_utf8(headers).map_err(|_| malformed("part headers are not utf-8"))?;
+        let header = |name: &str| {
+            headers.split("\r\n")
+                .filter_map(|line| line.split_once(':'))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim())
+        };
+        let disposition = header("content-disposition").ok_or_else(|| malformed("part without Content-Disposition"))?;
+        let name = parameter(disposition, "name").ok_or_else(|| malformed("part without a name"))?;
+
+        match parameter(disposition, "filename") {
+            Some(file_name) => {
+                let path = upload_path(upload_dir);
+                // pushed before writing, so it gets cleaned up whatever happens next
+                multipart.files.push(UploadedFile {
+                    field: name,
+                    file_name,
+                    content_type: header("content-type").map(str::to_string),
+                    path: path.clone(),
+                    size: 0,
+                });
+                let mut file = io::BufWriter::new(File::create(&path)?);
+                let mut counted = Counted { inner: &mut file, count: 0 };
+                if !reader.copy_until(&delimiter, &mut counted, usize::MAX)? {
+                    return Err(malformed("file cut short"));
+                }
+                let size = counted.count;
+                file.flush()?;
+                if let Some(uploaded) = multipart.files.last_mut() {
+                    uploaded.size = size;
+                }
+            },
+            None => {
+                let mut value = Vec::new();
+                if !reader.copy_until(&delimiter, &mut value, MAX_FIELD_SIZE)? {
+                    return Err(malformed("field too big or cut short"));
+                }
+                let value = String::from_utf8(value).map_err(|_| malformed("field is not utf-8"))?;
+                multipart.fields.push((name, value));
+            },
+        }
+    }
+}
+
+// unique within the process, the directory may be shared with other processes though
+fn upload_path(dir: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    dir.join(format!("upload-{}-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed), nanos))
+}
+
+// Reads through a buffer so it can look for delimiters which span reads
+struct Delimited<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    eof: bool,
+    // reading failed rather than writing
+    read_failed: bool,
+}
+
+impl<R: Read> Delimited<R> {
+    // Copies everything up to the delimiter into the writer and skips the delimiter. False if
+    // the input ended first or more than `max` bytes would have to be copied.
+    fn copy_until(&mut self, delimiter: &[u8], writer: &mut impl Write, max: usize) -> io::Result<bool> {
+        let mut copied = 0;
+        loop {
+            if let Some(at) = find(&self.buffer, delimiter) {
+                if copied + at > max {
+                    return Ok(false);
+                }
+                writer.write_all(&self.buffer[..at])?;
+                self.buffer.drain(..at + delimiter.len());
+                return Ok(true);
+            }
+            // whatever cant be the start of the delimiter can go already
+            let safe = self.buffer.len().saturating_sub(delimiter.len() - 1);
+            copied += safe;
+            if copied > max {
+                return Ok(false);
+            }
+            writer.write_all(&self.buffer[..safe])?;
+            self.buffer.drain(..safe);
+            if !self.fill()? {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        while self.buffer.len() < buf.len() {
+            if !self.fill()? {
+                return Ok(false);
+            }
+        }
+        buf.copy_from_slice(&self.buffer[..buf.len()]);
+        self.buffer.drain(..buf.len());
+        Ok(true)
+    }
+
+    // false at the end of the input
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0; 16 * 1024];
+        let read = match self.reader.read(&mut chunk) {
+            Ok(read) => read,
+            Err(e) => {
+                self.read_failed = true;
+                return Err(e);
+            },
+        };
+        self.eof = read == 0;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+struct Counted<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{BodyStream, Framing};
+    use crate::router::Router;
+    use crate::server::{handle_connection, ConnectionOptions};
+    use crate::test_dir::TestDir;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Search {
+        q: String,
+        page: Option<u32>,
+    }
+
+    fn request(content_type: Option<&str>, body: &[u8]) -> Request {
+        let mut request = Request { method: "POST".to_string(), path: "/".to_string(), body: body.to_vec(), ..Default::default() };
+        if let Some(content_type) = content_type {
+            request.headers.insert("content-type".to_string(), content_type.to_string());
+        }
+        request
+    }
+
+    #[test]
+    fn query_strings() {
+        let mut request = request(None, b"");
+        request.query = Some("q=hello+w%C3%B6rld%21&page=2".to_string());
+        assert_eq!(Search { q: "hello wörld!".to_string(), page: Some(2) }, request.query().unwrap());
+
+        request.query = Some("q=a&extra=1".to_string());
+        let all: HashMap<String, String> = request.query().unwrap();
+        assert_eq!(Some("1"), all.get("extra").map(String::as_str));
+
+        for bad in ["page=2", "q=a&page=two", "q=%zz", "q=%ff"] {
+            request.query = Some(bad.to_string());
+            let error = request.query::<Search>().unwrap_err();
+            assert_eq!(400, error.status(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn form_bodies() {
+        let form = request(Some("application/x-www-form-urlencoded; charset=utf-8"), b"q=a%26b&page=1");
+        assert_eq!(Search { q: "a&b".to_string(), page: Some(1) }, form.form().unwrap());
+        let json = request(Some("application/json"), b"q=a");
+        assert_eq!(415, json.form::<Search>().unwrap_err().status());
+        assert_eq!(415, request(None, b"q=a").form::<Search>().unwrap_err().status());
+    }
+
+    #[test]
+    fn json_bodies() {
+        let json = request(Some("application/json"), br#"{"q": "rust", "page": 3}"#);
+        assert_eq!(Search { q: "rust".to_string(), page: Some(3) }, json.json().unwrap());
+        assert!(request(Some("application/merge-patch+json"), br#"{"q": ""}"#).json::<Search>().is_ok());
+        assert_eq!(400, request(Some("application/json"), b"{\"q\": ").json::<Search>().unwrap_err().status());
+        assert_eq!(400, request(Some("application/json"), b"{\"page\": 1}").json::<Search>().unwrap_err().status());
+        assert_eq!(415, request(Some("text/plain"), b"{}").json::<Search>().unwrap_err().status());
+        let response = Response::from(request(Some("text/plain"), b"{}").json::<Search>().unwrap_err());
+        assert_eq!(415, response.status);
+        let response = Response::json(200, &HashMap::from([("q", "rust")]));
+        assert_eq!((Some("application/json"), Some(&br#"{"q":"rust"}"#[..])), (response.header("content-type"), response.body.as_bytes()));
+    }
+
+    #[test]
+    fn multipart_uploads_go_to_disk() {
+        let dir = TestDir::new("uploads");
+        let content = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut body = b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nMy \"file\"\r\n\
+            --XyZ\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"data.bin\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n".to_vec();
+        body.extend_from_slice(&content);
+        body.extend_from_slice(b"\r\n--XyZ--\r\nepilogue");
+        let request = request(Some("multipart/form-data; boundary=\"XyZ\""), &body);
+
+        let multipart = request.multipart(&dir).unwrap();
+        assert_eq!(Some("My \"file\""), multipart.field("title"));
+        let upload = multipart.file("upload").unwrap();
+        assert_eq!(("data.bin", Some("application/octet-stream")), (upload.file_name.as_str(), upload.content_type.as_deref()));
+        assert_eq!(100_000, upload.size);
+        assert_eq!(content, fs::read(&upload.path).unwrap());
+
+        let path = upload.path.clone();
+        drop(multipart);
+        assert!(!path.exists());
+
+        let multipart = request.multipart(&dir).unwrap();
+        let kept = dir.join("kept.bin");
+        multipart.files.into_iter().next().unwrap().persist(&kept).unwrap();
+        assert_eq!(content, fs::read(&kept).unwrap());
+    }
+
+    #[test]
+    fn multipart_reads_a_streamed_body() {
+        let dir = TestDir::new("streamed-uploads");
+        let mut chunked = Vec::new();
+        for part in [&b"--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\r\n"[..], b"hello", b", world\r\n--b--\r\n"] {
+            chunked.extend_from_slice(format!("{:x}\r\n", part.len()).as_bytes());
+            chunked.extend_from_slice(part);
+            chunked.extend_from_slice(b"\r\n");
+        }
+        chunked.extend_from_slice(b"0\r\n\r\nnext request");
+        let mut request = request(Some("multipart/form-data; boundary=b"), b"");
+        let (stream, lent) = BodyStream::lend(io::Cursor::new(chunked), Framing::Chunked);
+        request.body_stream = Some(stream);
+
+        let multipart = request.multipart(&dir).unwrap();
+        assert_eq!(b"hello, world".to_vec(), fs::read(&multipart.file("f").unwrap().path).unwrap());
+        // the rest of the body is skipped, the reader is left at whatever comes after it
+        let (mut reader, finished) = lent.lock().unwrap().give_back();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!((true, "next request"), (finished, rest.as_str()));
+        assert!(request.body_reader().read(&mut [0; 1]).is_err());
+    }
+
+    #[test]
+    fn cut_short_streamed_uploads_are_a_400() {
+        let dir = TestDir::new("cut-short-uploads");
+        let head = "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=b\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let part = "--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a\"\r\n\r\nhalf a file";
+        // the connection closes in the middle of a chunk, then a chunk size which isnt one
+        for body in [format!("100\r\n{}", part), format!("{:x}\r\n{}\r\nzz\r\n", part.len(), part)] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let upload_dir = dir.to_path_buf();
+            let server = thread::spawn(move || {
+                let mut router = Router::new();
+                router.streaming("POST", "/upload", move |request| match request.multipart(&upload_dir) {
+                    Ok(_) => Response::new(201),
+                    Err(e) => e.into(),
+                });
+                let (stream, _) = listener.accept().unwrap();
+                handle_connection(stream, &router, &ConnectionOptions::default());
+            });
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(head.as_bytes()).unwrap();
+            client.write_all(body.as_bytes()).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+            server.join().unwrap();
+        }
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+    }
+
+    #[test]
+    fn malformed_multipart_is_a_400() {
+        let dir = TestDir::new("malformed-uploads");
+        for (content_type, body) in [
+            ("multipart/form-data", &b"--a\r\n\r\n--a--"[..]),
+            ("multipart/form-data; boundary=a", b"no boundary here"),
+            ("multipart/form-data; boundary=a", b"--a\r\nContent-Disposition: form-data; name=\"x\"\r\n\r\ncut short"),
+            ("multipart/form-data; boundary=a", b"--a\r\nContent-Type: text/plain\r\n\r\nno name\r\n--a--"),
+            ("multipart/form-data; boundary=a", b"--a\r\nContent-Disposition: form-data; name=\"f\"; filename=\"x\"\r\n\r\nhalf a file"),
+        ] {
+            let error = request(Some(content_type), body).multipart(&dir).unwrap_err();
+            assert_eq!(400, error.status(), "{:?}", String::from_utf8_lossy(body));
+        }
+        // the cut short upload didnt leave anything behind
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+    }
+}
@@ -0,0 +1,943 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::websocket::Stream;
+
+/// A parsed HTTP/1.x request
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    pub method: String,
+    /// The request target without the query string, e.g. `/index.html`
+    pub path: String,
+    /// Everything after the `?` in the request target, if there was one
+    pub query: Option<String>,
+    /// e.g. `HTTP/1.1`
+    pub version: String,
+    /// Header names are lowercased, repeated headers are joined with `, `
+    pub headers: HashMap<String, String>,
+    /// Empty for routes added with [`Router::streaming`](crate::router::Router::streaming),
+    /// their body is in `body_stream` instead. [`body_reader`](Request::body_reader) reads
+    /// either one.
+    pub body: Vec<u8>,
+    /// What is left of the body on the connection, for the handler to read as it goes
+    pub body_stream: Option<BodyStream>,
+    /// Filled in by the [`Router`](crate::router::Router) from `:name` and `*name` parts of the route
+    pub params: HashMap<String, String>,
+    /// Values middleware passes on to handlers, like the request id or the authenticated user
+    pub extensions: HashMap<String, String>,
+}
+
+/// A response for a handler to return, `Content-Length` or `Transfer-Encoding` is added when
+/// it is written out
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    /// Takes over the connection once the response is written, for `101 Switching Protocols`
+    pub upgrade: Option<Upgrade>,
+}
+
+/// Runs on the raw connection after a protocol switch
+pub struct Upgrade(Box<UpgradeFn>);
+
+type UpgradeFn = dyn FnOnce(&mut dyn Stream) + Send;
+
+impl Upgrade {
+    pub fn run(self, stream: &mut dyn Stream) {
+        (self.0)(stream)
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+/// What gets sent after the response headers
+pub enum Body {
+    /// Already in memory, sent with a `Content-Length`
+    Full(Vec<u8>),
+    /// Read and sent a piece at a time. With a `Content-Length` when the length is known up
+    /// front, chunked otherwise.
+    Stream {
+        reader: Box<dyn Read + Send>,
+        length: Option<u64>,
+    },
+    /// Not sent at all, for HEAD requests. The length is still sent when known, so the
+    /// headers match what a GET would get.
+    Omitted {
+        length: Option<u64>,
+    },
+}
+
+impl Body {
+    /// The whole body, None if it is streamed
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Full(bytes) => Some(bytes),
+            Body::Stream { .. } | Body::Omitted { .. } => None,
+        }
+    }
+
+    /// None for streams of unknown length
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Full(bytes) => Some(bytes.len() as u64),
+            Body::Stream { length, .. } | Body::Omitted { length } => *length,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Reads a streamed body into memory
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Full(bytes) => Ok(bytes),
+            Body::Stream { mut reader, .. } => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            },
+            Body::Omitted { .. } => Ok(Vec::new()),
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Full(bytes) => write!(f, "Full({} bytes)", bytes.len()),
+            Body::Stream { length, .. } => write!(f, "Stream(length: {:?})", length),
+            Body::Omitted { length } => write!(f, "Omitted(length: {:?})", length),
+        }
+    }
+}
+
+// Adapts an iterator of chunks into a reader, for generated bodies
+struct ChunkReader<I> {
+    chunks: I,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Read for ChunkReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.next() {
+                Some(chunk) => self.current = io::Cursor::new(chunk),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The client closed the connection without sending anything, not really an error
+    ConnectionClosed,
+    Io(io::Error),
+    /// Anything that isnt valid HTTP, answered with a 400
+    Malformed(&'static str),
+    /// Something like `HTTP/2.0` on the request line, answered with a 505
+    UnsupportedVersion,
+    /// The request line is longer than [`Limits::max_request_line`], answered with a 414
+    UriTooLong,
+    /// More header bytes or lines than [`Limits`] allows, answered with a 431
+    HeadersTooLarge,
+    /// A body bigger than [`Limits::max_body`], answered with a 413
+    BodyTooLarge,
+}
+
+/// How much of a request is read before giving up on it, so a client cant make us buffer
+/// whatever it likes
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_request_line: usize,
+    /// All header lines together, including the line endings
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    /// Except for routes added with [`Router::streaming`](crate::router::Router::streaming)
+    pub max_body: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_request_line: 8 * 1024,
+            max_header_bytes: 16 * 1024,
+            max_headers: 100,
+            max_body: 10 * 1024 * 1024,
+        }
+    }
+}
+
+impl ParseError {
+    /// The status to respond with, there is nobody left to respond to for some of them
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ParseError::ConnectionClosed | ParseError::Io(_) => None,
+            ParseError::Malformed(_) => Some(400),
+            ParseError::UnsupportedVersion => Some(505),
+            ParseError::UriTooLong => Some(414),
+            ParseError::HeadersTooLarge => Some(431),
+            ParseError::BodyTooLarge => Some(413),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::ConnectionClosed => write!(f, "connection closed before a request was sent"),
+            ParseError::Io(e) => write!(f, "io error while reading request: {}", e),
+            ParseError::Malformed(reason) => write!(f, "malformed request: {}", reason),
+            ParseError::UnsupportedVersion => write!(f, "unsupported http version"),
+            ParseError::UriTooLong => write!(f, "request line too long"),
+            ParseError::HeadersTooLarge => write!(f, "request headers too large"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
+impl Request {
+    /// Reads one request (request line, headers and body) off the reader.
+    ///
+    /// The body is read according to `Transfer-Encoding: chunked` or `Content-Length`,
+    /// without either of those the request has no body.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        Request::read_with_limits(reader, &Limits::default())
+    }
+
+    /// Like [`read_from`](Request::read_from), but stops reading at the given limits
+    pub fn read_with_limits<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        let mut request = Request::read_head(reader, limits)?;
+        request.body = read_body(reader, &request, limits)?;
+        Ok(request)
+    }
+
+    // The request line and headers, the body is left for read_body
+    pub(crate) fn read_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        // clients are allowed to send empty lines before the request line
+        let request_line = loop {
+            let line = read_line(reader, limits.max_request_line).map_err(|e| match e {
+                ParseError::HeadersTooLarge => ParseError::UriTooLong,
+                e => e,
+            })?;
+            match line {
+                None => return Err(ParseError::ConnectionClosed),
+                Some(line) if line.is_empty() => continue,
+                Some(line) => break line,
+            }
+        };
+
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) => (method, target, version),
+            _ => return Err(ParseError::Malformed("request line is not METHOD TARGET VERSION")),
+        };
+        if method.is_empty() || !method.bytes().all(is_token_byte) {
+            return Err(ParseError::Malformed("invalid method"));
+        }
+        if !(target.starts_with('/') || target == "*" || target.contains("://")) {
+            return Err(ParseError::Malformed("invalid request target"));
+        }
+        match version {
+            "HTTP/1.1" | "HTTP/1.0" => {},
+            v if v.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+            _ => return Err(ParseError::Malformed("invalid http version")),
+        }
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+
+        let headers = read_headers(reader, limits)?;
+        Ok(Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query,
+            version: version.to_string(),
+            headers,
+            body: Vec::new(),
+            body_stream: None,
+            params: HashMap::new(),
+            extensions: HashMap::new(),
+        })
+    }
+
+    /// Looks up a header, ignoring the case of the name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(|value| value.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+
+    pub fn extension(&self, name: &str) -> Option<&str> {
+        self.extensions.get(name).map(|value| value.as_str())
+    }
+
+    /// Reads the body, from `body_stream` if it is still on the connection and from `body`
+    /// otherwise
+    pub fn body_reader(&self) -> BodyReader<'_> {
+        BodyReader { body: &self.body, stream: self.body_stream.as_ref() }
+    }
+}
+
+/// See [`Request::body_reader`]
+pub struct BodyReader<'a> {
+    body: &'a [u8],
+    stream: Option<&'a BodyStream>,
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream {
+            Some(stream) => stream.read(buf),
+            None => self.body.read(buf),
+        }
+    }
+}
+
+/// A request body which the server left on the connection, decoded as it is read. Only
+/// readable while the handler runs, the connection goes back to the server after that.
+#[derive(Clone)]
+pub struct BodyStream(Arc<Mutex<dyn LentBody>>);
+
+impl BodyStream {
+    // The handler reads through the BodyStream, the server takes the reader back through the
+    // other half once the handler returns
+    pub(crate) fn lend<R>(reader: R, framing: Framing) -> (BodyStream, Arc<Mutex<Lent<R>>>)
+        where R: BufRead + Send + 'static
+    {
+        let lent = Arc::new(Mutex::new(Lent { reader: Some(reader), decoder: Decoder::new(framing) }));
+        (BodyStream(lent.clone()), lent)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).read(buf)
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+trait LentBody: Send {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+pub(crate) struct Lent<R> {
+    reader: Option<R>,
+    decoder: Decoder,
+}
+
+impl<R: BufRead + Send> LentBody for Lent<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.reader {
+            Some(reader) => self.decoder.read(reader, buf),
+            // someone kept a clone of the request around
+            None => Err(io::Error::other("the request body is gone, its handler already returned")),
+        }
+    }
+}
+
+// Reading this much of a body the handler didnt want is cheaper than a new connection
+const MAX_UNREAD: u64 = 64 * 1024;
+
+impl<R: BufRead + Send> Lent<R> {
+    /// Gives the reader back once the handler is done with it, past whatever body the handler
+    /// left unread. The bool says whether that worked out, otherwise the connection isnt at the
+    /// start of the next request and has to be closed.
+    pub(crate) fn give_back(&mut self) -> (R, bool) {
+        let mut buffer = [0; 8 * 1024];
+        let mut skipped = 0;
+        let finished = loop {
+            match self.read(&mut buffer) {
+                Ok(0) => break true,
+                Ok(read) if skipped + (read as u64) <= MAX_UNREAD => skipped += read as u64,
+                _ => break false,
+            }
+        };
+        (self.reader.take().expect("reader is only given back once"), finished)
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response { status, headers: Vec::new(), body: Body::Full(Vec::new()), upgrade: None }
+    }
+
+    pub fn html(status: u16, body: impl Into<Vec<u8>>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body)
+    }
+
+    /// Serializes the value, a value which cant be serialized is logged and answered with a 500
+    pub fn json<T: serde::Serialize + ?Sized>(status: u16, value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::new(status).with_header("Content-Type", "application/json").with_body(body),
+            Err(e) => {
+                error!("Failed to serialize response: {}", e);
+                Response::text(500, "Internal Server Error")
+            },
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Full(body.into());
+        self
+    }
+
+    /// Streams the body from the reader instead of holding it in memory. Pass the length if
+    /// it is known, otherwise the body is sent chunked.
+    pub fn with_reader<R>(mut self, reader: R, length: Option<u64>) -> Response
+        where R: Read + Send + 'static
+    {
+        self.body = Body::Stream { reader: Box::new(reader), length };
+        self
+    }
+
+    /// Hands the connection to `upgrade` once this response is written, nothing else is read
+    /// from or written to it by the server after that
+    pub fn with_upgrade<F>(mut self, upgrade: F) -> Response
+        where F: FnOnce(&mut dyn Stream) + Send + 'static
+    {
+        self.upgrade = Some(Upgrade(Box::new(upgrade)));
+        self
+    }
+
+    /// Streams a generated body, each item is sent as it is produced
+    pub fn with_chunks<I>(self, chunks: I) -> Response
+        where
+            I: IntoIterator<Item = Vec<u8>>,
+            I::IntoIter: Send + 'static,
+    {
+        let reader = ChunkReader { chunks: chunks.into_iter(), current: io::Cursor::new(Vec::new()) };
+        self.with_reader(reader, None)
+    }
+
+    /// Replaces the header if there already is one by that name, otherwise adds it
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        match self.headers.iter_mut().find(|(existing, _)| existing.eq_ignore_ascii_case(name)) {
+            Some((_, existing)) => *existing = value.to_string(),
+            None => self.headers.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    /// Adds a request header name to `Vary`, unless it is already listed
+    pub fn add_vary(&mut self, name: &str) {
+        let vary = match self.header("vary") {
+            Some(vary) if vary.split(',').any(|v| v.trim().eq_ignore_ascii_case(name) || v.trim() == "*") => return,
+            Some(vary) => format!("{}, {}", vary, name),
+            None => name.to_string(),
+        };
+        self.set_header("Vary", &vary);
+    }
+
+    /// Looks up a header, ignoring the case of the name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Writes the status line, headers and body, returns how many bytes of body were sent.
+    /// Flushing is left to the caller, so pipelined responses can go out together.
+    ///
+    /// A streamed body of unknown length is sent chunked, which HTTP/1.0 clients dont
+    /// understand, so read it into memory first for those.
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<u64> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // these never have a body, so they dont get a length either
+        let has_body = !(self.status < 200 || self.status == 204 || self.status == 304);
+        let omitted = matches!(self.body, Body::Omitted { .. });
+        match self.body.len() {
+            Some(length) if has_body => head.push_str(&format!("Content-Length: {}\r\n", length)),
+            None if has_body && !omitted => head.push_str("Transfer-Encoding: chunked\r\n"),
+            _ => {},
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+        if !has_body {
+            return Ok(0);
+        }
+        match &mut self.body {
+            Body::Full(bytes) => {
+                writer.write_all(bytes)?;
+                Ok(bytes.len() as u64)
+            },
+            Body::Stream { reader, length: Some(length) } => {
+                let copied = io::copy(&mut reader.take(*length), writer)?;
+                // the length is already out, so the client would wait for the rest forever
+                if copied < *length {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body shorter than its length"));
+                }
+                Ok(copied)
+            },
+            Body::Stream { reader, length: None } => write_chunked(reader, writer),
+            Body::Omitted { .. } => Ok(0),
+        }
+    }
+}
+
+pub(crate) fn write_chunked<R: Read + ?Sized, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+    let mut buffer = vec![0; 16 * 1024];
+    let mut sent = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        write!(writer, "{:x}\r\n", read)?;
+        writer.write_all(&buffer[..read])?;
+        writer.write_all(b"\r\n")?;
+        // a generated body may take a while between pieces, so dont sit on this one
+        writer.flush()?;
+        sent += read as u64;
+    }
+    writer.write_all(b"0\r\n\r\n")?;
+    Ok(sent)
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// Decodes `%XX` escapes, None if an escape is broken or the result isnt utf-8
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// tchar from RFC 9110, the characters allowed in methods and header names
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Reads a line without its line ending, or None at the end of the stream
+// Lines longer than max (without the line ending) are a HeadersTooLarge
+pub(crate) fn read_line<R: BufRead>(reader: &mut R, max: usize) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    // room for the \r\n as well
+    if reader.take(max as u64 + 2).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') && line.len() == max + 2 {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(ParseError::Malformed("connection closed in the middle of a line"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| ParseError::Malformed("request is not valid utf-8"))
+}
+
+fn read_headers<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<HashMap<String, String>, ParseError> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut budget = limits.max_header_bytes;
+    let mut count = 0;
+    loop {
+        let line = read_line(reader, budget)?.ok_or(ParseError::Malformed("connection closed in the headers"))?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        count += 1;
+        if count > limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        budget = budget.saturating_sub(line.len() + 2);
+        if line.starts_with([' ', '\t']) {
+            return Err(ParseError::Malformed("obsolete header line folding"));
+        }
+        let (name, value) = line.split_once(':').ok_or(ParseError::Malformed("header line without a colon"))?;
+        // whitespace before the colon is explicitly forbidden
+        if name.is_empty() || !name.bytes().all(is_token_byte) {
+            return Err(ParseError::Malformed("invalid header name"));
+        }
+        let value = value.trim_matches([' ', '\t']);
+        headers
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+}
+
+/// How a request says its body ends, checked before any of it is read
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Framing {
+    Empty,
+    Length(u64),
+    Chunked,
+}
+
+pub(crate) fn framing(request: &Request) -> Result<Framing, ParseError> {
+    let content_length = request.header("content-length");
+    match request.header("transfer-encoding") {
+        // a request with both could be read differently by us and a proxy in front of us
+        Some(_) if content_length.is_some() => {
+            Err(ParseError::Malformed("both Content-Length and Transfer-Encoding"))
+        },
+        Some(encoding) => {
+            let is_chunked = encoding.rsplit(',').next().map(|last| last.trim().eq_ignore_ascii_case("chunked"));
+            if is_chunked != Some(true) {
+                return Err(ParseError::Malformed("request body is not chunked"));
+            }
+            Ok(Framing::Chunked)
+        },
+        None => match content_length {
+            None => Ok(Framing::Empty),
+            Some(length) => {
+                if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(ParseError::Malformed("invalid Content-Length"));
+                }
+                length.parse().map(Framing::Length).map_err(|_| ParseError::Malformed("invalid Content-Length"))
+            },
+        },
+    }
+}
+
+pub(crate) fn read_body<R: BufRead>(reader: &mut R, request: &Request, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    match framing(request)? {
+        Framing::Empty => Ok(Vec::new()),
+        Framing::Length(length) => {
+            if length > limits.max_body {
+                return Err(ParseError::BodyTooLarge);
+            }
+            let mut body = Vec::new();
+            reader.take(length).read_to_end(&mut body)?;
+            if (body.len() as u64) < length {
+                return Err(ParseError::Malformed("connection closed in the body"));
+            }
+            Ok(body)
+        },
+        Framing::Chunked => read_chunked_body(reader, limits),
+    }
+}
+
+/// Decodes a body a piece at a time instead of all at once like [`read_body`], for bodies
+/// which are passed on as they arrive. Not limited in size, whoever reads it decides.
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    chunked: bool,
+    // left of the body, or of the current chunk
+    remaining: u64,
+    done: bool,
+    failed: bool,
+}
+
+impl Decoder {
+    pub(crate) fn new(framing: Framing) -> Decoder {
+        match framing {
+            Framing::Empty => Decoder { chunked: false, remaining: 0, done: true, failed: false },
+            Framing::Length(length) => Decoder { chunked: false, remaining: length, done: length == 0, failed: false },
+            Framing::Chunked => Decoder { chunked: true, remaining: 0, done: false, failed: false },
+        }
+    }
+
+    /// Reads the next piece of the body off the reader, 0 once all of it is read
+    pub(crate) fn read<R: BufRead>(&mut self, reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        // after an error there is no telling where in the body the reader is
+        if self.failed {
+            return Err(io::Error::other("reading the body already failed"));
+        }
+        let read = self.read_more(reader, buf);
+        self.failed = read.is_err();
+        read
+    }
+
+    fn read_more<R: BufRead>(&mut self, reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chunked && self.remaining == 0 {
+            let line = read_line(reader, MAX_CHUNK_LINE)
+                .map_err(|e| match e {
+                    ParseError::HeadersTooLarge => invalid("chunk size line too long"),
+                    e => parse_error(e),
+                })?
+                .ok_or_else(closed_in_body)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+            if self.remaining == 0 {
+                // trailer fields are read so the connection is left after the body, then ignored
+                read_headers(reader, &Limits::default()).map_err(parse_error)?;
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let wanted = self.remaining.min(buf.len() as u64) as usize;
+        let read = reader.read(&mut buf[..wanted])?;
+        if read == 0 {
+            return Err(closed_in_body());
+        }
+        self.remaining -= read as u64;
+        if self.remaining == 0 {
+            if !self.chunked {
+                self.done = true;
+            } else if read_line(reader, 0).map_err(parse_error)?.is_none_or(|line| !line.is_empty()) {
+                return Err(invalid("chunk is longer than its size"));
+            }
+        }
+        Ok(read)
+    }
+}
+
+fn parse_error(e: ParseError) -> io::Error {
+    match e {
+        ParseError::Io(e) => e,
+        e => invalid(&e.to_string()),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn closed_in_body() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the body")
+}
+
+// chunk size lines are just a number and maybe an extension nobody uses
+pub(crate) const MAX_CHUNK_LINE: usize = 1024;
+
+fn read_chunked_body<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader, MAX_CHUNK_LINE)
+            .map_err(|e| match e {
+                ParseError::HeadersTooLarge => ParseError::Malformed("chunk size line too long"),
+                e => e,
+            })?
+            .ok_or(ParseError::Malformed("connection closed in the body"))?;
+        // chunk extensions after a ; are allowed, nobody uses them so they are ignored
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| ParseError::Malformed("invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        // the size is the client's, adding to it could overflow
+        if size > limits.max_body.saturating_sub(body.len() as u64) {
+            return Err(ParseError::BodyTooLarge);
+        }
+        let before = body.len();
+        reader.take(size).read_to_end(&mut body)?;
+        if ((body.len() - before) as u64) < size {
+            return Err(ParseError::Malformed("connection closed in the body"));
+        }
+        match read_line(reader, 0) {
+            Ok(Some(line)) if line.is_empty() => {},
+            Err(ParseError::Io(e)) => return Err(ParseError::Io(e)),
+            _ => return Err(ParseError::Malformed("chunk is longer than its size")),
+        }
+    }
+    // trailer fields are read so the connection is left at the next request, then ignored
+    read_headers(reader, limits)?;
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::read_from(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let request = parse("GET /search?q=rust HTTP/1.1\r\nHost: localhost\r\nAccept: a\r\naccept: b\r\n\r\n").unwrap();
+        assert_eq!("GET", request.method);
+        assert_eq!("/search", request.path);
+        assert_eq!(Some("q=rust".to_string()), request.query);
+        assert_eq!("HTTP/1.1", request.version);
+        assert_eq!(Some("localhost"), request.header("HOST"));
+        assert_eq!(Some("a, b"), request.header("Accept"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn reads_body_by_content_length() {
+        let request = parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, and some more").unwrap();
+        assert_eq!(b"hello", &request.body[..]);
+        assert!(matches!(parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello"), Err(ParseError::Malformed(_))));
+    }
+
+    #[test]
+    fn reads_chunked_body() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+        assert_eq!(b"hello, world", &parse(raw).unwrap().body[..]);
+    }
+
+    #[test]
+    fn decoder_stops_at_the_first_error() {
+        let mut decoder = Decoder::new(Framing::Chunked);
+        let mut reader = &b"zz\r\n5\r\nhello\r\n0\r\n\r\n"[..];
+        assert!(decoder.read(&mut reader, &mut [0; 16]).is_err());
+        // not another read off the connection, it could wait for a body which never comes
+        assert!(decoder.read(&mut reader, &mut [0; 16]).is_err());
+        assert!(reader.starts_with(b"5\r\nhello"));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(Some("a b/ü".to_string()), percent_decode("a%20b%2F%C3%BC"));
+        assert_eq!(None, percent_decode("100%"));
+        assert_eq!(None, percent_decode("%zz"));
+        assert_eq!(None, percent_decode("%FF"));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        for raw in [
+            "GET /\r\n\r\n",
+            "GET  / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost localhost\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost : localhost\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: localhost\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+        ] {
+            assert_eq!(Some(400), parse(raw).unwrap_err().status(), "{:?}", raw);
+        }
+        assert_eq!(Some(505), parse("GET / HTTP/2.0\r\n\r\n").unwrap_err().status());
+        assert!(matches!(parse(""), Err(ParseError::ConnectionClosed)));
+    }
+
+    fn limited(raw: &str, limits: &Limits) -> Option<u16> {
+        Request::read_with_limits(&mut raw.as_bytes(), limits).err().and_then(|e| e.status())
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let limits = Limits { max_request_line: 20, max_header_bytes: 30, max_headers: 2, max_body: 5 };
+        assert_eq!(None, limited("GET /0123456789 HTTP/1.1\r\n\r\n", &Limits { max_request_line: 24, ..limits.clone() }));
+        assert_eq!(Some(414), limited("GET /0123456789 HTTP/1.1\r\n\r\n", &limits));
+        assert_eq!(Some(431), limited("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n", &limits));
+        assert_eq!(Some(431), limited("GET / HTTP/1.1\r\nA: 0123456789012345678901234567\r\n\r\n", &limits));
+        assert_eq!(None, limited("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", &limits));
+        assert_eq!(Some(413), limited("POST / HTTP/1.1\r\nContent-Length: 6\r\n\r\nhello!", &limits));
+        assert_eq!(Some(413), limited("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n", &limits));
+        // a chunk size which would wrap around when added to what was read so far
+        assert_eq!(Some(413), limited("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n", &limits));
+    }
+
+    fn written(mut response: Response) -> (String, u64) {
+        let mut out = Vec::new();
+        let sent = response.write_to(&mut out).unwrap();
+        (String::from_utf8(out).unwrap(), sent)
+    }
+
+    #[test]
+    fn writes_streams_with_a_length_or_chunked() {
+        let response = Response::new(200).with_reader(&b"hello, world"[..], Some(5));
+        assert_eq!(("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello".to_string(), 5), written(response));
+
+        let chunks = vec![b"hello".to_vec(), Vec::new(), b", world".to_vec()];
+        let (out, sent) = written(Response::new(200).with_chunks(chunks));
+        assert_eq!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n", out);
+        assert_eq!(12, sent);
+
+        // the chunked output reads back as a chunked request body would
+        let raw = format!("POST / HTTP/1.1\r\n{}", out.split_once("\r\n").unwrap().1);
+        assert_eq!(b"hello, world", &parse(&raw).unwrap().body[..]);
+
+        let mut response = Response::new(200).with_reader(&b"short"[..], Some(10));
+        assert!(response.write_to(&mut Vec::new()).is_err());
+    }
+}
@@ -1,8 +1,31 @@
+#[macro_use]
+pub mod log;
+pub mod compression;
+pub mod config;
+pub mod extract;
+pub mod http;
+pub mod metrics;
+pub mod middleware;
+pub mod proxy;
+pub mod range;
+pub mod router;
+pub mod server;
+pub mod static_files;
+pub mod template;
+#[cfg(test)]
+mod test_dir;
+pub mod tls;
+pub mod tokio_server;
+pub mod websocket;
+
 use std::sync::mpsc::Receiver;
 use std::thread;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
 
 struct Worker {
     id: usize,
@@ -10,25 +33,34 @@ struct Worker {
 }
 
 impl Worker {
-    pub fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Worker
+    pub fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>, stats: Arc<PoolStats>) -> Worker
     {
         let handle = thread::spawn(move || {
             loop {
-                println!("inside worker #{}, waiting for lock and message on receiver!", id);
+                debug!("worker#{} waiting for a job", id);
                 // NOTE: dividing the receiver locking and msg receiving up into two different
                 // parts is counter productive because it makes it that the lock is held until the
                 // end of the scope (i.e. after job()) rather than immediately unlocking after the
                 // let statement
                 // let receiver_raw: &Receiver<Job> = &*receiver.lock().expect("failed to acquire lock");
                 // let job = receiver_raw.recv().expect("didnt receive message successfully in thread via the channel");
-                let job = receiver.lock().expect("failed to acquire lock").recv();
+                // jobs run outside of the lock, so it can only be poisoned by a panic in recv itself
+                let job = receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recv();
                 match job {
                     Ok(j) => {
-                        println!("executing job on worker#{}", id);
-                        j();
+                        debug!("executing job on worker#{}", id);
+                        stats.queued.fetch_sub(1, Ordering::Relaxed);
+                        stats.busy.fetch_add(1, Ordering::Relaxed);
+                        // a panicking job would otherwise take the worker thread down with it
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(j)) {
+                            error!("job on worker#{} panicked: {}", id, panic_message(payload.as_ref()));
+                            stats.panicked.fetch_add(1, Ordering::Relaxed);
+                        }
+                        stats.busy.fetch_sub(1, Ordering::Relaxed);
+                        stats.completed.fetch_add(1, Ordering::Relaxed);
                     },
                     Err(_) => {
-                        println!("worker#{} disconnected, shutting down thread", id);
+                        debug!("worker#{} disconnected, shutting down thread", id);
                         return;
                     }
                 }
@@ -40,9 +72,57 @@ impl Worker {
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// The message passed to `panic!`, if it was a string
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: Option<mpsc::Sender<Job>>,
+    stats: Arc<PoolStats>,
+}
+
+/// What a [`ThreadPool`] is up to, for telling whether it has the right number of workers.
+/// A queue which keeps growing while every worker is busy means it is too small.
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    workers: usize,
+    busy: AtomicUsize,
+    queued: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+}
+
+impl PoolStats {
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Workers running a job right now
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    /// Jobs waiting for a free worker
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Jobs finished since the pool started, the panicked ones included
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    pub fn panicked(&self) -> u64 {
+        self.panicked.load(Ordering::Relaxed)
+    }
 }
 
 impl ThreadPool {
@@ -51,11 +131,16 @@ impl ThreadPool {
         assert!(size > 0);
         let (sender, receiver) = mpsc::channel();
         let receiver_protected = Arc::new(Mutex::new(receiver));
+        let stats = Arc::new(PoolStats { workers: size, ..Default::default() });
         let mut workers = Vec::with_capacity(size);
         for i in 0..workers.capacity() {
-            workers.push(Worker::new(i, Arc::clone(&receiver_protected)));
+            workers.push(Worker::new(i, Arc::clone(&receiver_protected), Arc::clone(&stats)));
         }
-        ThreadPool { workers, sender: Option::Some(sender) }
+        ThreadPool { workers, sender: Option::Some(sender), stats }
+    }
+
+    pub fn stats(&self) -> Arc<PoolStats> {
+        Arc::clone(&self.stats)
     }
 
     pub fn execute<F>(&self, f: F)
@@ -63,24 +148,88 @@ impl ThreadPool {
             F: FnOnce(),
             F: Send + 'static,
     {
-        println!("executing function in thread pool");
         let func = Box::new(f);
 
         if let Some(sender) = &self.sender {
-            sender.send(func).expect("Couldnt send job successfully to a worker thread");
+            self.stats.queued.fetch_add(1, Ordering::Relaxed);
+            // only fails once every worker is gone, then there is nobody to run it anyway
+            if sender.send(func).is_err() {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                error!("Couldnt send job to a worker thread, dropping it");
+            }
         }
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
+    /// Stops taking new jobs and waits for the queued and running ones to finish, but only
+    /// until the deadline. Returns false if some workers were still busy by then, those are
+    /// left running in the background.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        self.join_workers(Some(Instant::now() + timeout))
+    }
+
+    fn join_workers(&mut self, deadline: Option<Instant>) -> bool {
         // dropping the sender channel will make all future .recv calls inside threads
         drop(self.sender.take());
+        let mut all_finished = true;
         for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
             if let Some(handle) = worker.handle.take() {
-                handle.join().unwrap();
+                debug!("Shutting down worker {}", worker.id);
+                if let Some(deadline) = deadline {
+                    while !handle.is_finished() && Instant::now() < deadline {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    if !handle.is_finished() {
+                        // dropping the handle detaches the thread instead of waiting for it
+                        warn!("worker {} is still busy, not waiting for it", worker.id);
+                        all_finished = false;
+                        continue;
+                    }
+                }
+                if handle.join().is_err() {
+                    error!("worker {} had panicked", worker.id);
+                }
             }
         }
+        all_finished
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.join_workers(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worker_survives_a_panicking_job() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(|| panic!("job went wrong"));
+        pool.execute(move || sender.send("still alive").unwrap());
+        assert_eq!(Ok("still alive"), receiver.recv_timeout(Duration::from_secs(5)));
+        assert!(pool.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn keeps_stats() {
+        let pool = ThreadPool::new(1);
+        let stats = pool.stats();
+        let (started, wait) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        });
+        pool.execute(|| {});
+        wait.recv().unwrap();
+        assert_eq!((1, 1, 1, 0), (stats.workers(), stats.busy(), stats.queued(), stats.completed()));
+        release.send(()).unwrap();
+        pool.execute(|| panic!("counted"));
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!((0, 0, 3, 1), (stats.busy(), stats.queued(), stats.completed(), stats.panicked()));
     }
 }
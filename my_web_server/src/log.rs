@@ -0,0 +1,252 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::http::{Request, Response};
+
+/// Severity of a diagnostics message, messages below the configured level are dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    pub fn parse(name: &str) -> Option<Level> {
+        match name.to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Used by the `error!`, `warn!`, `info!` and `debug!` macros, diagnostics go to stderr
+pub fn write(level: Level, args: fmt::Arguments) {
+    if enabled(level) {
+        let level = format!("{:?}", level).to_uppercase();
+        eprintln!("{} {:<5} {}", rfc3339(SystemTime::now()), level, args);
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// `host - - [time] "request line" status bytes latency_us`
+    Common,
+    /// Common plus the referer and user agent
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl AccessLogFormat {
+    pub fn parse(name: &str) -> Option<AccessLogFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "common" => Some(AccessLogFormat::Common),
+            "combined" => Some(AccessLogFormat::Combined),
+            "json" => Some(AccessLogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// One line per answered request, shared by all the workers
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AccessLog").field("format", &self.format).finish_non_exhaustive()
+    }
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat, writer: Box<dyn Write + Send>) -> AccessLog {
+        AccessLog { format, writer: Mutex::new(writer) }
+    }
+
+    pub fn stdout(format: AccessLogFormat) -> AccessLog {
+        AccessLog::new(format, Box::new(io::stdout()))
+    }
+
+    /// Appends to the file, creating it if needed
+    pub fn file(format: AccessLogFormat, path: &Path) -> io::Result<AccessLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::new(format, Box::new(file)))
+    }
+
+    /// `latency` is the time from having read the request to having written the response
+    pub fn record(
+        &self,
+        client: Option<SocketAddr>,
+        request: &Request,
+        response: &Response,
+        bytes: u64,
+        latency: Duration,
+    ) {
+        let line = self.format_line(SystemTime::now(), client, request, response, bytes, latency);
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // losing a log line is not worth failing the request over
+        let _ = writeln!(writer, "{}", line).and_then(|_| writer.flush());
+    }
+
+    fn format_line(
+        &self,
+        time: SystemTime,
+        client: Option<SocketAddr>,
+        request: &Request,
+        response: &Response,
+        bytes: u64,
+        latency: Duration,
+    ) -> String {
+        let client = client.map_or("-".to_string(), |address| address.ip().to_string());
+        let target = match &request.query {
+            Some(query) => format!("{}?{}", request.path, query),
+            None => request.path.clone(),
+        };
+        let referer = request.header("referer").unwrap_or("-");
+        let user_agent = request.header("user-agent").unwrap_or("-");
+        match self.format {
+            AccessLogFormat::Common | AccessLogFormat::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {}",
+                    client, clf_time(time), request.method, target, request.version, response.status,
+                    if bytes == 0 { "-".to_string() } else { bytes.to_string() },
+                );
+                if self.format == AccessLogFormat::Combined {
+                    line.push_str(&format!(" \"{}\" \"{}\"", referer, user_agent));
+                }
+                line.push_str(&format!(" {}", latency.as_micros()));
+                line
+            },
+            AccessLogFormat::Json => format!(
+                "{{\"time\":\"{}\",\"client\":\"{}\",\"method\":\"{}\",\"path\":\"{}\",\"version\":\"{}\",\"status\":{},\"bytes\":{},\"latency_ms\":{:.3},\"referer\":\"{}\",\"user_agent\":\"{}\"}}",
+                rfc3339(time), client, json_escape(&request.method), json_escape(&target), json_escape(&request.version),
+                response.status, bytes, latency.as_secs_f64() * 1000.0, json_escape(referer), json_escape(user_agent),
+            ),
+        }
+    }
+}
+
+// The parts of an http date, "Tue, 10 Oct 2000 13:55:36 GMT", which is always in UTC
+fn date_parts(time: SystemTime) -> (String, String, String, String) {
+    let date = httpdate::fmt_http_date(time);
+    let parts: Vec<&str> = date.split(' ').collect();
+    (parts[1].to_string(), parts[2].to_string(), parts[3].to_string(), parts[4].to_string())
+}
+
+// 10/Oct/2000:13:55:36 +0000
+fn clf_time(time: SystemTime) -> String {
+    let (day, month, year, clock) = date_parts(time);
+    format!("{}/{}/{}:{} +0000", day, month, year, clock)
+}
+
+// 2000-10-10T13:55:36Z
+fn rfc3339(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (day, month, year, clock) = date_parts(time);
+    let month = MONTHS.iter().position(|m| *m == month).unwrap_or(0) + 1;
+    format!("{}-{:02}-{}T{}Z", year, month, day, clock)
+}
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn example() -> (SystemTime, Option<SocketAddr>, Request, Response) {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        let mut request = Request {
+            method: "GET".to_string(),
+            path: "/apache_pb.gif".to_string(),
+            query: Some("a=1".to_string()),
+            version: "HTTP/1.0".to_string(),
+            ..Default::default()
+        };
+        request.headers.insert("user-agent".to_string(), "curl \"8\"".to_string());
+        let response = Response::new(200);
+        (time, Some("127.0.0.1:54321".parse().unwrap()), request, response)
+    }
+
+    #[test]
+    fn common_and_combined_format() {
+        let (time, client, request, response) = example();
+        let latency = Duration::from_micros(1500);
+        let common = AccessLog::stdout(AccessLogFormat::Common).format_line(time, client, &request, &response, 2326, latency);
+        assert_eq!("127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?a=1 HTTP/1.0\" 200 2326 1500", common);
+        let combined = AccessLog::stdout(AccessLogFormat::Combined).format_line(time, client, &request, &response, 2326, latency);
+        assert!(combined.ends_with("200 2326 \"-\" \"curl \"8\"\" 1500"));
+    }
+
+    #[test]
+    fn json_format() {
+        let (time, client, request, response) = example();
+        let line = AccessLog::stdout(AccessLogFormat::Json).format_line(time, client, &request, &response, 2326, Duration::from_micros(1500));
+        assert!(line.starts_with("{\"time\":\"2000-10-10T13:55:36Z\",\"client\":\"127.0.0.1\",\"method\":\"GET\""));
+        assert!(line.contains("\"status\":200,\"bytes\":2326,\"latency_ms\":1.500"));
+        assert!(line.ends_with("\"user_agent\":\"curl \\\"8\\\"\"}"));
+    }
+
+    #[test]
+    fn levels() {
+        assert!(Level::Error < Level::Debug);
+        assert_eq!(Some(Level::Warn), Level::parse("WARN"));
+    }
+}
@@ -1,47 +1,139 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::{BufReader, BufRead, Write};
-use std::fs;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::thread;
 use std::time::Duration;
-use my_web_server::ThreadPool;
+use my_web_server::{error, info, warn};
+use my_web_server::config::{Config, Runtime, USAGE};
+use my_web_server::log::{self, AccessLog};
+use my_web_server::metrics::Metrics;
+use my_web_server::middleware::{RequestId, Timing};
+use my_web_server::proxy::Proxy;
+use my_web_server::router::Router;
+use my_web_server::server::{serve, shutdown_signal};
+use my_web_server::static_files::StaticFiles;
+use my_web_server::template::{Context, Templates};
+use my_web_server::tls;
+use my_web_server::tokio_server;
+use my_web_server::websocket::Message;
 
 fn main() {
-    let listener = TcpListener::bind(("localhost",7878)).expect("Failed to bind tcp socket for listening");
-    println!("Hello, world!");
-    let thread_pool = ThreadPool::new(3);
-    // automatically shut down server after 2 requests
-    for connection_stream in listener.incoming().take(2) {
-        let stream = connection_stream.expect("Failed to connect to socket");
-        println!("Connection established!");
-        thread_pool.execute(|| {
-            handle_connection(stream);
+    let mut config = Config::build(std::env::args()).unwrap_or_else(|err| {
+        eprintln!("Problem parsing args: {}\n{}", err, USAGE);
+        std::process::exit(1);
+    });
+
+    log::set_level(config.log_level);
+    if let Some(format) = config.access_log_format {
+        let access_log = match &config.access_log_file {
+            Some(path) => AccessLog::file(format, path).unwrap_or_else(|err| {
+                eprintln!("Failed to open access log {}: {}", path.display(), err);
+                std::process::exit(1);
+            }),
+            None => AccessLog::stdout(format),
+        };
+        config.connection.access_log = Some(Arc::new(access_log));
+    }
+    if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+        let tls = tls::server_config(cert, key).unwrap_or_else(|err| {
+            eprintln!("Failed to load TLS certificate: {}", err);
+            std::process::exit(1);
         });
+        config.connection.tls = Some(tls);
+    }
+    if config.metrics {
+        config.connection.metrics = Some(Arc::new(Metrics::new()));
+    }
+
+    let listener = TcpListener::bind((config.address.as_str(), config.port)).unwrap_or_else(|err| {
+        eprintln!("Failed to listen on {}:{}: {}", config.address, config.port, err);
+        std::process::exit(1);
+    });
+    let scheme = if config.connection.tls.is_some() { "https" } else { "http" };
+    info!("Hello, world! Listening on {}://{}:{}", scheme, config.address, config.port);
+    let router = Arc::new(routes(&config));
+    // serve forever, unless asked to shut down after a number of connections or by a signal
+    match shutdown_signal() {
+        Ok(flag) => config.connection.shutdown = flag,
+        Err(err) => warn!("Failed to register signal handlers, stopping will be abrupt: {}", err),
+    }
+    if let Some(port) = config.redirect_port {
+        redirect_plaintext(&config, port);
+    }
+    let served = match config.runtime {
+        Runtime::Threads => serve(listener, &config, router),
+        Runtime::Tokio => tokio_server::serve(listener, &config, router),
+    };
+    let finished = served.unwrap_or_else(|err| {
+        eprintln!("Application error: {}", err);
+        std::process::exit(1);
+    });
+    if !finished {
+        warn!("Requests still running after {:?}, exiting anyway", config.shutdown_timeout);
+        std::process::exit(1);
+    }
+    if config.connection.shutdown.load(Ordering::SeqCst) {
+        info!("Shut down cleanly");
     }
-    println!("Shutting down");
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader: BufReader<&TcpStream> = BufReader::new(&stream);
-    let http_request: Vec<String> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
-    // println!("Received HTTP request: {:#?}", http_request);
-    let first_line : &String = http_request.first().expect("Did not get even one line http request");
+// Serves the https redirect in the background, it goes away with the main listener
+fn redirect_plaintext(config: &Config, port: u16) {
+    let listener = TcpListener::bind((config.address.as_str(), port)).unwrap_or_else(|err| {
+        eprintln!("Failed to listen on {}:{}: {}", config.address, port, err);
+        std::process::exit(1);
+    });
+    info!("Redirecting http://{}:{} to https", config.address, port);
+    let mut redirect_config = config.clone();
+    redirect_config.max_connections = None;
+    redirect_config.connection.tls = None;
+    // the metrics are about the real server, not its redirects
+    redirect_config.connection.metrics = None;
+    let host = config.public_host.clone().unwrap_or_else(|| config.address.clone());
+    let router = Arc::new(tls::redirect_to_https(host, config.port));
+    thread::spawn(move || {
+        if let Err(err) = serve(listener, &redirect_config, router) {
+            error!("Redirect listener failed: {}", err);
+        }
+    });
+}
 
-    let (status_line, file_name) = match first_line.as_str() {
-        "GET / HTTP/1.1" => { ("HTTP/1.1 200 OK", "hello.html") },
-        "GET /sleep HTTP/1.1" => {
-            // println!("Sleeping for 5 seconds to simulate a slow response");
-            thread::sleep(Duration::from_secs(5));
-            ("HTTP/1.1 200 OK", "hello.html")
-        },
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html")
-    };
-    let contents = fs::read_to_string(file_name).expect("Failed to read html file");
-    let headers = format!("Content-Length: {}\r\n", contents.len());
-    let response = format!("{}\r\n{}\r\n{}", status_line, headers, contents);
-    // println!("Responding with response:\n{}", response);
-    stream.write_all(response.as_bytes()).expect("Failed to write response to tcp stream");
+// new endpoints go here, handle_connection does not need to know about them
+fn routes(config: &Config) -> Router {
+    let templates = Arc::new(Templates::new(&config.templates));
+    let mut router = Router::new();
+    if let Some(metrics) = config.connection.metrics.clone() {
+        router.get("/metrics", move |_| metrics.response());
+    }
+    for (prefix, upstreams) in &config.proxies {
+        let proxy = Proxy::new(upstreams.clone())
+            .with_timeouts(Duration::from_secs(5), config.proxy_timeout)
+            .with_health_check(&config.health_check, Duration::from_secs(10));
+        router.streaming("*", &format!("{}/*path", prefix), move |request| proxy.forward(request, request.param("path").unwrap_or("")));
+    }
+    let hello = Arc::clone(&templates);
+    router.get("/", move |_| hello.page(200, "hello.html", &Context::new().with("title", "Hello!")));
+    let hello = Arc::clone(&templates);
+    router.get("/sleep", move |_| {
+        // println!("Sleeping for 5 seconds to simulate a slow response");
+        thread::sleep(Duration::from_secs(5));
+        hello.page(200, "hello.html", &Context::new().with("title", "Hello!"))
+    });
+    router.websocket("/echo", |_, socket| {
+        while let Ok(Some(message)) = socket.recv() {
+            // pings and the close are answered by recv already
+            let echo = matches!(message, Message::Text(_) | Message::Binary(_));
+            if echo && socket.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let files = StaticFiles::new(&config.document_root).with_listing(Arc::clone(&templates), "listing.html");
+    router.get("/static/*path", move |request| files.serve(request, request.param("path").unwrap_or("")));
+    router.not_found(move |request| {
+        templates.page(404, "404.html", &Context::new().with("title", "Not Found").with("path", request.path.as_str()))
+    });
+    // cross cutting things go here, e.g. .wrap(Cors::any_origin()) or .wrap(BasicAuth::with_user(..))
+    router.wrap(RequestId::new()).wrap(Timing);
+    router
 }
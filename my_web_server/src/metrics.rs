@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::http::{Request, Response};
+use crate::PoolStats;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Counts what the server does, for scraping in the Prometheus text format.
+///
+/// Requests are labelled with the route pattern they matched rather than their path, so
+/// `/users/:id` is one series no matter how many users there are.
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use my_web_server::metrics::Metrics;
+/// use my_web_server::router::Router;
+///
+/// let metrics = Arc::new(Metrics::new());
+/// let mut router = Router::new();
+/// let scraped = Arc::clone(&metrics);
+/// router.get("/metrics", move |_| scraped.response());
+/// // and `config.connection.metrics = Some(metrics)` so the server records into it
+/// ```
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<RequestLabels, u64>>,
+    latencies: Mutex<BTreeMap<String, Histogram>>,
+    open_connections: AtomicUsize,
+    connections: AtomicU64,
+    pool: OnceLock<Arc<PoolStats>>,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct RequestLabels {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // not cumulative, that is done when rendering
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+// requests which didnt match a route all go into one series
+const UNMATCHED: &str = "unmatched";
+
+// any token is a valid method, so a client could otherwise make up a new series per request
+const METHODS: [&str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+const OTHER_METHOD: &str = "other";
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Reports on the pool from now on. Only the first pool counts, a server with more than
+    /// one listener should give its metrics to the main one.
+    pub fn watch_pool(&self, stats: Arc<PoolStats>) {
+        let _ = self.pool.set(stats);
+    }
+
+    pub fn record(&self, request: &Request, response: &Response, latency: Duration) {
+        let route = request.extension("route").unwrap_or(UNMATCHED);
+        let method = METHODS.iter().find(|method| **method == request.method).copied().unwrap_or(OTHER_METHOD);
+        let labels = RequestLabels { method: method.to_string(), route: route.to_string(), status: response.status };
+        *lock(&self.requests).entry(labels).or_insert(0) += 1;
+
+        let seconds = latency.as_secs_f64();
+        let mut latencies = lock(&self.latencies);
+        let histogram = latencies.entry(route.to_string()).or_default();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// Counts the connection as open until the guard is dropped
+    pub fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { metrics: Arc::clone(self) }
+    }
+
+    /// Everything in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        header(&mut out, "http_requests_total", "counter", "Requests answered, by route and status");
+        for (labels, count) in lock(&self.requests).iter() {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                escape(&labels.method), escape(&labels.route), labels.status, count
+            );
+        }
+
+        header(&mut out, "http_request_duration_seconds", "histogram", "Time from reading a request to having written its response");
+        for (route, histogram) in lock(&self.latencies).iter() {
+            let route = escape(route);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}", route, bound, cumulative);
+            }
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}", route, histogram.count);
+            let _ = writeln!(out, "http_request_duration_seconds_sum{{route=\"{}\"}} {}", route, histogram.sum);
+            let _ = writeln!(out, "http_request_duration_seconds_count{{route=\"{}\"}} {}", route, histogram.count);
+        }
+
+        header(&mut out, "http_connections_open", "gauge", "Connections currently open");
+        let _ = writeln!(out, "http_connections_open {}", self.open_connections.load(Ordering::Relaxed));
+        header(&mut out, "http_connections_total", "counter", "Connections accepted");
+        let _ = writeln!(out, "http_connections_total {}", self.connections.load(Ordering::Relaxed));
+
+        if let Some(pool) = self.pool.get() {
+            for (name, kind, help, value) in [
+                ("threadpool_workers", "gauge", "Worker threads in the pool", pool.workers() as u64),
+                ("threadpool_busy_workers", "gauge", "Workers running a job", pool.busy() as u64),
+                ("threadpool_queued_jobs", "gauge", "Jobs waiting for a free worker", pool.queued() as u64),
+                ("threadpool_jobs_completed_total", "counter", "Jobs finished", pool.completed()),
+                ("threadpool_jobs_panicked_total", "counter", "Jobs which panicked", pool.panicked()),
+            ] {
+                header(&mut out, name, kind, help);
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+        }
+        out
+    }
+
+    pub fn response(&self) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .with_body(self.render())
+    }
+}
+
+/// Keeps a connection counted as open, see [`Metrics::connection_opened`]
+pub struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// a panic while holding the lock cant leave a counter half updated
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// label values are quoted, so backslashes, quotes and newlines need escaping
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPool;
+
+    fn request(method: &str, route: Option<&str>) -> Request {
+        let mut request = Request { method: method.to_string(), path: "/users/1".to_string(), ..Default::default() };
+        if let Some(route) = route {
+            request.extensions.insert("route".to_string(), route.to_string());
+        }
+        request
+    }
+
+    #[test]
+    fn counts_requests_by_route_and_status() {
+        let metrics = Metrics::new();
+        metrics.record(&request("GET", Some("/users/:id")), &Response::new(200), Duration::from_millis(3));
+        metrics.record(&request("GET", Some("/users/:id")), &Response::new(200), Duration::from_millis(30));
+        metrics.record(&request("GET", Some("/users/:id")), &Response::new(500), Duration::from_secs(20));
+        metrics.record(&request("POST", Some("/a\"b")), &Response::new(404), Duration::from_millis(1));
+        metrics.record(&request("GET", None), &Response::new(404), Duration::from_millis(1));
+        metrics.record(&request("MADEUP1", None), &Response::new(405), Duration::from_millis(1));
+        metrics.record(&request("MADEUP2", None), &Response::new(405), Duration::from_millis(1));
+        let text = metrics.render();
+        for line in [
+            "# TYPE http_requests_total counter",
+            "http_requests_total{method=\"GET\",route=\"/users/:id\",status=\"200\"} 2",
+            "http_requests_total{method=\"GET\",route=\"/users/:id\",status=\"500\"} 1",
+            "http_requests_total{method=\"POST\",route=\"/a\\\"b\",status=\"404\"} 1",
+            "http_requests_total{method=\"GET\",route=\"unmatched\",status=\"404\"} 1",
+            "http_requests_total{method=\"other\",route=\"unmatched\",status=\"405\"} 2",
+            "http_request_duration_seconds_bucket{route=\"/users/:id\",le=\"0.005\"} 1",
+            "http_request_duration_seconds_bucket{route=\"/users/:id\",le=\"0.025\"} 1",
+            "http_request_duration_seconds_bucket{route=\"/users/:id\",le=\"0.05\"} 2",
+            "http_request_duration_seconds_bucket{route=\"/users/:id\",le=\"10\"} 2",
+            "http_request_duration_seconds_bucket{route=\"/users/:id\",le=\"+Inf\"} 3",
+            "http_request_duration_seconds_count{route=\"/users/:id\"} 3",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}\n{}", line, text);
+        }
+        assert!(!text.contains("MADEUP"));
+    }
+
+    #[test]
+    fn reports_connections_and_the_pool() {
+        let metrics = Arc::new(Metrics::new());
+        let pool = ThreadPool::new(2);
+        metrics.watch_pool(pool.stats());
+        let first = metrics.connection_opened();
+        let _second = metrics.connection_opened();
+        drop(first);
+        let text = metrics.response().body.into_bytes().unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("\nhttp_connections_open 1\n"), "{}", text);
+        assert!(text.contains("\nhttp_connections_total 2\n"));
+        assert!(text.contains("\nthreadpool_workers 2\n"));
+        assert!(text.contains("\nthreadpool_queued_jobs 0\n"));
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+
+use crate::http::{Request, Response};
+
+/// Runs around every request the [`Router`](crate::router::Router) handles.
+///
+/// It can change the request before passing it on with `next.run(request)`, change the
+/// response coming back, or answer by itself without calling `next` at all.
+///
+/// ```
+/// use my_web_server::http::{Request, Response};
+/// use my_web_server::middleware::Next;
+/// use my_web_server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.wrap(|request: &mut Request, next: Next| {
+///     if request.header("x-api-key") != Some("secret") {
+///         return Response::text(403, "Forbidden");
+///     }
+///     next.run(request).with_header("X-Checked", "yes")
+/// });
+/// ```
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next) -> Response;
+}
+
+impl<F> Middleware for F
+    where F: Fn(&mut Request, Next) -> Response + Send + Sync
+{
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        self(request, next)
+    }
+}
+
+/// The middleware after this one, and the route handler at the end of them
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    endpoint: &'a dyn Fn(&mut Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middleware: &'a [Box<dyn Middleware>], endpoint: &'a dyn Fn(&mut Request) -> Response) -> Next<'a> {
+        Next { middleware, endpoint }
+    }
+
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+/// Gives every request an id, in `request.extension("request_id")` and the `X-Request-Id`
+/// response header. An id sent by the client (or a proxy in front) is kept if it looks sane.
+pub struct RequestId {
+    prefix: u64,
+    counter: AtomicU64,
+}
+
+impl RequestId {
+    pub fn new() -> RequestId {
+        // differs between runs, so ids from before a restart dont come up again
+        RequestId { prefix: RandomState::new().build_hasher().finish(), counter: AtomicU64::new(0) }
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> RequestId {
+        RequestId::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let id = match request.header("x-request-id") {
+            Some(id) if is_valid_request_id(id) => id.to_string(),
+            _ => format!("{:016x}-{}", self.prefix, self.counter.fetch_add(1, Ordering::Relaxed)),
+        };
+        request.extensions.insert("request_id".to_string(), id.clone());
+        let mut response = next.run(request);
+        response.set_header("X-Request-Id", &id);
+        response
+    }
+}
+
+// it ends up in headers and logs, so nothing which could break those
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+}
+
+/// Adds a `Server-Timing` header with how long the rest of the pipeline took
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let started = Instant::now();
+        let mut response = next.run(request);
+        let timing = format!("app;dur={:.3}", started.elapsed().as_secs_f64() * 1000.0);
+        let timing = match response.header("server-timing") {
+            Some(existing) => format!("{}, {}", existing, timing),
+            None => timing,
+        };
+        response.set_header("Server-Timing", &timing);
+        response
+    }
+}
+
+/// Cross origin resource sharing: answers preflight requests and adds the
+/// `Access-Control-*` headers for allowed origins. Requests from other origins are passed
+/// on without them, so the browser keeps their responses from the page.
+pub struct Cors {
+    /// None allows any origin
+    origins: Option<Vec<String>>,
+    methods: Vec<String>,
+    /// Empty allows whatever headers the preflight asks for
+    headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    pub fn any_origin() -> Cors {
+        Cors {
+            origins: None,
+            methods: ["GET", "HEAD", "POST", "PUT", "DELETE"].iter().map(|m| m.to_string()).collect(),
+            headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Origins are compared exactly, e.g. `https://example.com`
+    pub fn with_origins(origins: &[&str]) -> Cors {
+        Cors { origins: Some(origins.iter().map(|o| o.to_string()).collect()), ..Cors::any_origin() }
+    }
+
+    pub fn allow_methods(mut self, methods: &[&str]) -> Cors {
+        self.methods = methods.iter().map(|m| m.to_ascii_uppercase()).collect();
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &[&str]) -> Cors {
+        self.headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Lets the browser send cookies along, the origin is then echoed instead of `*`
+    pub fn allow_credentials(mut self) -> Cors {
+        self.credentials = true;
+        self
+    }
+
+    /// How long browsers may cache the preflight answer
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn is_allowed(&self, origin: &str) -> bool {
+        self.origins.as_ref().is_none_or(|origins| origins.iter().any(|allowed| allowed == origin))
+    }
+
+    fn allow_origin(&self, response: &mut Response, origin: &str) {
+        if self.origins.is_none() && !self.credentials {
+            response.set_header("Access-Control-Allow-Origin", "*");
+        } else {
+            response.set_header("Access-Control-Allow-Origin", origin);
+            response.add_vary("Origin");
+        }
+        if self.credentials {
+            response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let Some(origin) = request.header("origin").map(|origin| origin.to_string()) else {
+            return next.run(request);
+        };
+        if !self.is_allowed(&origin) {
+            return next.run(request);
+        }
+        let preflight = request.method == "OPTIONS" && request.header("access-control-request-method").is_some();
+        if !preflight {
+            let mut response = next.run(request);
+            self.allow_origin(&mut response, &origin);
+            return response;
+        }
+
+        let mut response = Response::new(204);
+        self.allow_origin(&mut response, &origin);
+        response.set_header("Access-Control-Allow-Methods", &self.methods.join(", "));
+        let headers = match (self.headers.is_empty(), request.header("access-control-request-headers")) {
+            (false, _) => Some(self.headers.join(", ")),
+            (true, Some(requested)) => Some(requested.to_string()),
+            (true, None) => None,
+        };
+        if let Some(headers) = headers {
+            response.set_header("Access-Control-Allow-Headers", &headers);
+        }
+        if let Some(max_age) = self.max_age {
+            response.set_header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+        response.add_vary("Access-Control-Request-Method");
+        response.add_vary("Access-Control-Request-Headers");
+        response
+    }
+}
+
+type Credentials = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// HTTP basic authentication, the user name ends up in `request.extension("user")`.
+/// Only use it over TLS, the password is sent along with every request.
+pub struct BasicAuth {
+    realm: String,
+    check: Credentials,
+}
+
+impl BasicAuth {
+    /// `check` gets the user name and password and says whether they are valid
+    pub fn new<F>(realm: &str, check: F) -> BasicAuth
+        where F: Fn(&str, &str) -> bool + Send + Sync + 'static
+    {
+        BasicAuth { realm: realm.to_string(), check: Box::new(check) }
+    }
+
+    /// Lets in a single user
+    pub fn with_user(realm: &str, user: &str, password: &str) -> BasicAuth {
+        let (user, password) = (user.to_string(), password.to_string());
+        BasicAuth::new(realm, move |u, p| {
+            // both are compared in full, so the time taken doesnt give away which was wrong
+            constant_time_eq(u.as_bytes(), user.as_bytes()) & constant_time_eq(p.as_bytes(), password.as_bytes())
+        })
+    }
+
+    fn credentials(request: &Request) -> Option<(String, String)> {
+        let (scheme, encoded) = request.header("authorization")?.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some((user.to_string(), password.to_string()))
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        match BasicAuth::credentials(request) {
+            Some((user, password)) if (self.check)(&user, &password) => {
+                request.extensions.insert("user".to_string(), user);
+                next.run(request)
+            },
+            _ => Response::text(401, "Unauthorized")
+                .with_header("WWW-Authenticate", &format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        let mut request = Request { method: method.to_string(), path: "/".to_string(), ..Default::default() };
+        for (name, value) in headers {
+            request.headers.insert(name.to_string(), value.to_string());
+        }
+        request
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/", |request| {
+            let user = request.extension("user").unwrap_or("nobody");
+            Response::text(200, format!("hello {}", user))
+        });
+        router
+    }
+
+    #[test]
+    fn runs_in_the_order_added() {
+        let mut router = router();
+        router
+            .wrap(|request: &mut Request, next: Next| next.run(request).with_header("X-Order", "outer"))
+            .wrap(|request: &mut Request, next: Next| next.run(request).with_header("X-Order", "inner"))
+            .wrap(|request: &mut Request, next: Next| {
+                if request.header("x-block").is_some() {
+                    return Response::text(403, "Forbidden");
+                }
+                next.run(request)
+            });
+        let response = router.handle(&mut request("GET", &[]));
+        let order: Vec<&str> = response.headers.iter().filter(|(n, _)| n == "X-Order").map(|(_, v)| v.as_str()).collect();
+        assert_eq!(vec!["inner", "outer"], order);
+        assert_eq!(403, router.handle(&mut request("GET", &[("x-block", "1")])).status);
+    }
+
+    #[test]
+    fn request_ids_and_timing() {
+        let mut router = router();
+        router.wrap(RequestId::new()).wrap(Timing);
+        let first = router.handle(&mut request("GET", &[]));
+        let second = router.handle(&mut request("GET", &[]));
+        assert_ne!(first.header("x-request-id"), second.header("x-request-id"));
+        assert!(first.header("server-timing").unwrap().starts_with("app;dur="));
+
+        let kept = router.handle(&mut request("GET", &[("x-request-id", "abc-123")]));
+        assert_eq!(Some("abc-123"), kept.header("x-request-id"));
+        let replaced = router.handle(&mut request("GET", &[("x-request-id", "bad\tid")]));
+        assert_ne!(Some("bad\tid"), replaced.header("x-request-id"));
+    }
+
+    #[test]
+    fn cors_preflight_and_simple_requests() {
+        let mut router = router();
+        router.wrap(Cors::with_origins(&["https://app.example"]).allow_credentials().max_age(Duration::from_secs(600)));
+
+        let preflight = router.handle(&mut request("OPTIONS", &[
+            ("origin", "https://app.example"),
+            ("access-control-request-method", "POST"),
+            ("access-control-request-headers", "content-type"),
+        ]));
+        assert_eq!(204, preflight.status);
+        assert_eq!(Some("https://app.example"), preflight.header("access-control-allow-origin"));
+        assert_eq!(Some("content-type"), preflight.header("access-control-allow-headers"));
+        assert_eq!(Some("600"), preflight.header("access-control-max-age"));
+        assert_eq!(Some("true"), preflight.header("access-control-allow-credentials"));
+
+        let simple = router.handle(&mut request("GET", &[("origin", "https://app.example")]));
+        assert_eq!(Some("https://app.example"), simple.header("access-control-allow-origin"));
+        assert_eq!(Some("Origin"), simple.header("vary"));
+
+        let other = router.handle(&mut request("GET", &[("origin", "https://evil.example")]));
+        assert_eq!(200, other.status);
+        assert_eq!(None, other.header("access-control-allow-origin"));
+
+        let mut router = self::router();
+        router.wrap(Cors::any_origin());
+        let any = router.handle(&mut request("GET", &[("origin", "https://anyone.example")]));
+        assert_eq!(Some("*"), any.header("access-control-allow-origin"));
+    }
+
+    #[test]
+    fn basic_auth() {
+        let mut router = router();
+        router.wrap(BasicAuth::with_user("admin area", "alice", "s3cret"));
+
+        let denied = router.handle(&mut request("GET", &[]));
+        assert_eq!(401, denied.status);
+        assert_eq!(Some("Basic realm=\"admin area\", charset=\"UTF-8\""), denied.header("www-authenticate"));
+        // alice:wrong
+        assert_eq!(401, router.handle(&mut request("GET", &[("authorization", "Basic YWxpY2U6d3Jvbmc=")])).status);
+        assert_eq!(401, router.handle(&mut request("GET", &[("authorization", "Basic !!!")])).status);
+
+        // alice:s3cret
+        let allowed = router.handle(&mut request("GET", &[("authorization", "Basic YWxpY2U6czNjcmV0")]));
+        assert_eq!(Some(&b"hello alice"[..]), allowed.body.as_bytes());
+    }
+}
@@ -0,0 +1,590 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::http::{self, read_line, Body, Decoder, Framing, Limits, ParseError, Request, Response};
+
+/// Forwards requests to upstream HTTP servers, which take turns answering them.
+///
+/// `Host` is set to the upstream, the client's is passed on in `X-Forwarded-Host` and the
+/// client's address is added to `X-Forwarded-For`. Upstream responses are streamed back as
+/// they arrive. So are request bodies on routes added with [`Router::streaming`], framed the
+/// way the client sent them, on other routes they are sent on from memory.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use my_web_server::proxy::Proxy;
+/// use my_web_server::router::Router;
+///
+/// let api = Proxy::new(["localhost:9000", "localhost:9001"])
+///     .with_health_check("/health", Duration::from_secs(10));
+/// let mut router = Router::new();
+/// router.streaming("*", "/api/*path", move |request| api.forward(request, request.param("path").unwrap_or("")));
+/// ```
+///
+/// [`Router::streaming`]: crate::router::Router::streaming
+pub struct Proxy {
+    upstreams: Arc<Upstreams>,
+    connect_timeout: Duration,
+    /// For each read and write once connected, a slower upstream gets a 504
+    timeout: Duration,
+}
+
+struct Upstreams {
+    list: Vec<Upstream>,
+    next: AtomicUsize,
+}
+
+struct Upstream {
+    /// `host:port`
+    address: String,
+    healthy: AtomicBool,
+}
+
+impl Proxy {
+    /// Upstreams are `host:port`, see [`upstream_address`] for turning urls into those
+    pub fn new<I>(upstreams: I) -> Proxy
+        where
+            I: IntoIterator,
+            I::Item: Into<String>,
+    {
+        let list = upstreams
+            .into_iter()
+            .map(|address| Upstream { address: address.into(), healthy: AtomicBool::new(true) })
+            .collect();
+        Proxy {
+            upstreams: Arc::new(Upstreams { list, next: AtomicUsize::new(0) }),
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn with_timeouts(mut self, connect_timeout: Duration, timeout: Duration) -> Proxy {
+        self.connect_timeout = connect_timeout;
+        self.timeout = timeout;
+        self
+    }
+
+    /// Requests `path` from every upstream every `interval` on a thread of its own, ones which
+    /// dont answer or answer with a 5xx are skipped until they pass again. The thread stops
+    /// once the proxy is dropped.
+    ///
+    /// Without health checks an upstream is only skipped after refusing a connection, until
+    /// the next one it accepts.
+    pub fn with_health_check(self, path: &str, interval: Duration) -> Proxy {
+        let upstreams = Arc::downgrade(&self.upstreams);
+        let path = path.to_string();
+        let timeout = self.connect_timeout;
+        let spawned = thread::Builder::new().name("health-check".to_string()).spawn(move || {
+            while let Some(upstreams) = upstreams.upgrade() {
+                for upstream in &upstreams.list {
+                    upstream.set_healthy(check(&upstream.address, &path, timeout));
+                }
+                drop(upstreams);
+                thread::sleep(interval);
+            }
+        });
+        if let Err(e) = spawned {
+            warn!("Failed to start health checks: {}", e);
+        }
+        self
+    }
+
+    /// Sends the request to the next upstream as `/{path}` plus the query string. If it cant
+    /// be reached the next one after it is tried, and so on. Once the request is sent it isnt
+    /// tried again anywhere else, it might not be safe to repeat.
+    ///
+    /// Answers 502 when no upstream could be reached or the answer made no sense, 504 when
+    /// the upstream took too long.
+    pub fn forward(&self, request: &Request, path: &str) -> Response {
+        let target = match &request.query {
+            Some(query) => format!("/{}?{}", path, query),
+            None => format!("/{}", path),
+        };
+        for upstream in self.upstreams.in_turn() {
+            let stream = match connect(&upstream.address, self.connect_timeout) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Failed to connect to upstream {}: {}", upstream.address, e);
+                    upstream.set_healthy(false);
+                    continue;
+                },
+            };
+            upstream.set_healthy(true);
+            return match self.exchange(stream, &upstream.address, request, &target) {
+                Ok(response) => response,
+                // nothing the upstream did wrong, and nobody to tell if the client went away
+                Err(Failure::Client(e)) => {
+                    debug!("Failed to read the body of {} {} from the client: {}", request.method, target, e);
+                    Response::text(400, "Bad Request")
+                },
+                Err(Failure::Upstream(e)) if is_timeout(&e) => {
+                    warn!("Upstream {} timed out on {} {}", upstream.address, request.method, target);
+                    Response::text(504, "Gateway Timeout")
+                },
+                Err(Failure::Upstream(e)) => {
+                    warn!("Upstream {} failed on {} {}: {}", upstream.address, request.method, target, e);
+                    Response::text(502, "Bad Gateway")
+                },
+            };
+        }
+        warn!("No upstream reachable for {} {}", request.method, target);
+        Response::text(502, "Bad Gateway")
+    }
+
+    fn exchange(&self, stream: TcpStream, address: &str, request: &Request, target: &str) -> Result<Response, Failure> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let connection = request.header("connection");
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, target, address);
+        for (name, value) in &request.headers {
+            // set below, except Expect which would get a 100 Continue that isnt passed back
+            let replaced = matches!(name.as_str(), "host" | "content-length" | "expect" | "x-forwarded-for" | "x-forwarded-host");
+            if !replaced && !is_hop_by_hop(name, connection) {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        if let Some(host) = request.header("host") {
+            head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+        }
+        let forwarded_for = match (request.header("x-forwarded-for"), request.extension("client")) {
+            (Some(earlier), Some(client)) => Some(format!("{}, {}", earlier, client)),
+            (earlier, client) => earlier.or(client).map(|value| value.to_string()),
+        };
+        if let Some(forwarded_for) = forwarded_for {
+            head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+        }
+        // a streamed body goes on the way it came in, the server has already checked its framing
+        let framing = match (&request.body_stream, http::framing(request)) {
+            (Some(_), Ok(framing)) => framing,
+            (None, Ok(Framing::Empty)) if request.body.is_empty() => Framing::Empty,
+            _ => Framing::Length(request.body.len() as u64),
+        };
+        match framing {
+            Framing::Empty => {},
+            Framing::Length(length) => head.push_str(&format!("Content-Length: {}\r\n", length)),
+            Framing::Chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+        }
+        // one request per connection keeps the framing simple, the upstream just closes it
+        head.push_str("Connection: close\r\n\r\n");
+        let mut writer = io::BufWriter::new(&stream);
+        writer.write_all(head.as_bytes())?;
+        let mut body = ClientBody { reader: request.body_reader(), failed: false };
+        let sent = if let Framing::Chunked = framing {
+            http::write_chunked(&mut body, &mut writer)
+        } else {
+            io::copy(&mut body, &mut writer)
+        };
+        if let Err(e) = sent {
+            return Err(if body.failed { Failure::Client(e) } else { Failure::Upstream(e) });
+        }
+        writer.flush()?;
+        drop(writer);
+
+        let mut reader = BufReader::new(stream);
+        let (status, headers) = loop {
+            let (status, headers) = read_head(&mut reader)?;
+            // interim responses like 103 Early Hints are dropped, 101 cant happen without Upgrade
+            if status >= 200 {
+                break (status, headers);
+            }
+        };
+        let connection = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("connection")).map(|(_, value)| value.clone());
+        let header = |wanted: &str| headers.iter().find(|(name, _)| name.eq_ignore_ascii_case(wanted)).map(|(_, value)| value.as_str());
+        let chunked = header("transfer-encoding").is_some_and(|encoding| {
+            encoding.rsplit(',').next().is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        });
+        let length = match header("content-length") {
+            Some(length) => Some(length.trim().parse::<u64>().map_err(|_| invalid("invalid Content-Length from upstream"))?),
+            None => None,
+        };
+
+        let mut response = Response::new(status);
+        for (name, value) in &headers {
+            // the length goes out again with the body, except for a 304 which has none to
+            // give it, there it is the length of what the client has cached
+            let length_from_body = name.eq_ignore_ascii_case("content-length") && status != 304;
+            if !length_from_body && !is_hop_by_hop(name, connection.as_deref()) {
+                response.headers.push((name.clone(), value.clone()));
+            }
+        }
+        if request.method == "HEAD" {
+            response.body = Body::Omitted { length };
+            return Ok(response);
+        }
+        if status == 204 || status == 304 {
+            return Ok(response);
+        }
+        Ok(match (chunked, length) {
+            (true, _) => response.with_reader(Dechunked { reader, decoder: Decoder::new(Framing::Chunked) }, None),
+            (false, Some(length)) => response.with_reader(reader.take(length), Some(length)),
+            // the body ends when the upstream closes the connection
+            (false, None) => response.with_reader(reader, None),
+        })
+    }
+}
+
+// Who an exchange went wrong for
+enum Failure {
+    /// Reading the request body, which the server streams from the client
+    Client(io::Error),
+    Upstream(io::Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Failure {
+        Failure::Upstream(e)
+    }
+}
+
+// The request body on its way to the upstream, keeping track of whether reading it failed
+struct ClientBody<'a> {
+    reader: http::BodyReader<'a>,
+    failed: bool,
+}
+
+impl Read for ClientBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf);
+        self.failed = read.is_err();
+        read
+    }
+}
+
+impl Upstreams {
+    // Healthy upstreams take turns, the rest are only tried when none of those can be reached
+    fn in_turn(&self) -> Vec<&Upstream> {
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        let (mut healthy, down): (Vec<_>, Vec<_>) = self.list.iter().partition(|upstream| upstream.healthy.load(Ordering::Relaxed));
+        if !healthy.is_empty() {
+            let len = healthy.len();
+            healthy.rotate_left(turn % len);
+        }
+        healthy.extend(down);
+        healthy
+    }
+}
+
+impl Upstream {
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                info!("Upstream {} is back", self.address);
+            } else {
+                warn!("Upstream {} is down", self.address);
+            }
+        }
+    }
+}
+
+/// Turns `http://host:port`, `host:port` or just `host` into `host:port`. None for anything
+/// else, https upstreams included.
+pub fn upstream_address(upstream: &str) -> Option<String> {
+    let address = upstream.strip_prefix("http://").unwrap_or(upstream).trim_end_matches('/');
+    if address.is_empty() || address.contains(['/', '?', '#', '@']) {
+        return None;
+    }
+    // the port is after the last colon, unless that colon is part of an IPv6 address
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && !port.ends_with(']') => {
+            port.parse::<u16>().ok().map(|_| address.to_string())
+        },
+        _ => Some(format!("{}:80", address)),
+    }
+}
+
+fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "address didnt resolve");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn check(address: &str, path: &str, timeout: Duration) -> bool {
+    let status = connect(address, timeout).and_then(|stream| {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        write!(&stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, address)?;
+        read_head(&mut BufReader::new(stream)).map(|(status, _)| status)
+    });
+    match status {
+        Ok(status) => status < 500,
+        Err(e) => {
+            debug!("Health check of {} failed: {}", address, e);
+            false
+        },
+    }
+}
+
+// The status line and headers of an upstream response. Unlike with requests, repeated headers
+// stay separate, Set-Cookie doesnt survive being joined with commas.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(u16, Vec<(String, String)>)> {
+    let limits = Limits::default();
+    let line = read_line(reader, limits.max_request_line)
+        .map_err(upstream_error)?
+        .ok_or_else(|| invalid("upstream closed the connection without answering"))?;
+    let mut parts = line.splitn(3, ' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status.parse::<u16>().ok(),
+        _ => None,
+    };
+    let status = status.filter(|status| (100..600).contains(status)).ok_or_else(|| invalid("invalid status line from upstream"))?;
+
+    let mut headers = Vec::new();
+    let mut budget = limits.max_header_bytes;
+    loop {
+        let line = read_line(reader, budget)
+            .map_err(upstream_error)?
+            .ok_or_else(|| invalid("upstream closed the connection in the headers"))?;
+        if line.is_empty() {
+            return Ok((status, headers));
+        }
+        if headers.len() == limits.max_headers {
+            return Err(invalid("too many headers from upstream"));
+        }
+        budget = budget.saturating_sub(line.len() + 2);
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("invalid header from upstream"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+// Headers which are only about one connection, plus whatever its Connection header lists
+fn is_hop_by_hop(name: &str, connection: Option<&str>) -> bool {
+    let name = name.to_ascii_lowercase();
+    matches!(name.as_str(), "connection" | "keep-alive" | "proxy-connection" | "te" | "trailer" | "transfer-encoding" | "upgrade")
+        || connection.is_some_and(|connection| connection.split(',').any(|token| token.trim().eq_ignore_ascii_case(&name)))
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn upstream_error(e: ParseError) -> io::Error {
+    match e {
+        ParseError::Io(e) => e,
+        e => invalid(&e.to_string()),
+    }
+}
+
+// Decodes a chunked upstream body, the server chunks it again on the way out if it needs to
+struct Dechunked<R> {
+    reader: R,
+    decoder: Decoder,
+}
+
+impl<R: BufRead> Read for Dechunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(&mut self.reader, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::router::Router;
+    use crate::server::serve;
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Instant;
+
+    // a real server to proxy to, for as many connections as given
+    fn upstream(router: Router, connections: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = Config { max_connections: Some(connections), ..Default::default() };
+        thread::spawn(move || serve(listener, &config, Arc::new(router)));
+        address
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    fn closed_port() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn forwards_requests_and_rewrites_headers() {
+        let mut router = Router::new();
+        router.any("/*path", |request| {
+            let seen = format!(
+                "{} {} {:?} host={} forwarded_host={} forwarded_for={} secret={:?} body={}",
+                request.method,
+                request.path,
+                request.query,
+                request.header("host").unwrap(),
+                request.header("x-forwarded-host").unwrap(),
+                request.header("x-forwarded-for").unwrap(),
+                request.header("x-secret"),
+                String::from_utf8_lossy(&request.body),
+            );
+            Response::new(201)
+                .with_header("Set-Cookie", "a=1")
+                .with_header("Set-Cookie", "b=2")
+                .with_chunks([seen.into_bytes()])
+        });
+        let address = upstream(router, 1);
+        let proxy = Proxy::new([address.to_string()]);
+
+        let mut request = Request {
+            method: "POST".to_string(),
+            path: "/api/users/1".to_string(),
+            query: Some("full=yes".to_string()),
+            version: "HTTP/1.1".to_string(),
+            body: b"hello".to_vec(),
+            ..Default::default()
+        };
+        for (name, value) in [("host", "example.com"), ("x-forwarded-for", "10.0.0.1"), ("connection", "x-secret"), ("x-secret", "1"), ("content-length", "5")] {
+            request.headers.insert(name.to_string(), value.to_string());
+        }
+        request.extensions.insert("client".to_string(), "127.0.0.2".to_string());
+
+        let response = proxy.forward(&request, "users/1");
+        assert_eq!(201, response.status);
+        assert_eq!(2, response.headers.iter().filter(|(name, _)| name == "Set-Cookie").count());
+        assert!(response.header("transfer-encoding").is_none());
+        assert_eq!(
+            format!("POST /users/1 Some(\"full=yes\") host={} forwarded_host=example.com forwarded_for=10.0.0.1, 127.0.0.2 secret=None body=hello", address),
+            body(response)
+        );
+    }
+
+    #[test]
+    fn streams_request_bodies_framed_as_they_came() {
+        let mut router = Router::new();
+        router.streaming("POST", "/*path", |request| {
+            // cut short when the client's body is
+            let size = io::copy(&mut request.body_reader(), &mut io::sink()).unwrap_or_default();
+            Response::text(200, format!("{} {:?} {:?}", size, request.header("content-length"), request.header("transfer-encoding")))
+        });
+        let proxy = Proxy::new([upstream(router, 3).to_string()]);
+        let mut router = Router::new();
+        router.streaming("*", "/*path", move |request| proxy.forward(request, request.param("path").unwrap_or("")));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // far below the bodies sent through
+        let mut config = Config { max_connections: Some(3), ..Default::default() };
+        config.connection.limits.max_body = 10;
+        thread::spawn(move || serve(listener, &config, Arc::new(router)));
+
+        let send = |head: &str, body: &[u8]| {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(head.as_bytes()).unwrap();
+            client.write_all(body).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = send("POST /a HTTP/1.1\r\nContent-Length: 100000\r\nConnection: close\r\n\r\n", &[b'x'; 100_000]);
+        assert!(response.ends_with("100000 Some(\"100000\") None"), "{}", response);
+        let chunked = b"5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n";
+        let response = send("POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n", chunked);
+        assert!(response.ends_with("12 None Some(\"chunked\")"), "{}", response);
+
+        // a body the client garbles isnt the upstream's fault
+        let response = send("POST /c HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n", b"5\r\nhello\r\nzz\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}", response);
+    }
+
+    #[test]
+    fn passes_the_length_through_for_head_and_304() {
+        let mut router = Router::new();
+        router.get("/big", |_| Response::new(200).with_body(vec![b'x'; 5000]));
+        router.get("/cached", |_| Response::new(304).with_header("ETag", "\"1\"").with_header("Content-Length", "5000"));
+        let address = upstream(router, 2);
+        let proxy = Proxy::new([address.to_string()]);
+
+        let mut request = Request { method: "HEAD".to_string(), ..Default::default() };
+        let response = proxy.forward(&request, "big");
+        assert!(matches!(response.body, Body::Omitted { length: Some(5000) }), "{:?}", response.body);
+
+        request.method = "GET".to_string();
+        let response = proxy.forward(&request, "cached");
+        assert_eq!(304, response.status);
+        assert_eq!(Some("5000"), response.header("content-length"));
+    }
+
+    #[test]
+    fn takes_turns_and_skips_upstreams_which_are_down() {
+        let upstreams = ["a", "b"].map(|name| {
+            let mut router = Router::new();
+            router.get("/", move |_| Response::text(200, name));
+            upstream(router, 2).to_string()
+        });
+        let down = closed_port();
+        let proxy = Proxy::new([upstreams[0].clone(), down.clone(), upstreams[1].clone()]);
+        let request = Request { method: "GET".to_string(), ..Default::default() };
+        let answers: Vec<String> = (0..4).map(|_| body(proxy.forward(&request, ""))).collect();
+        assert_eq!(vec!["a", "b", "a", "b"], answers);
+        assert!(!proxy.upstreams.list[1].healthy.load(Ordering::Relaxed));
+
+        let proxy = Proxy::new([down]);
+        assert_eq!(502, proxy.forward(&request, "").status);
+    }
+
+    #[test]
+    fn slow_upstreams_get_a_504() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // accepts but never answers
+        let silent = thread::spawn(move || listener.accept().map(|(stream, _)| {
+            thread::sleep(Duration::from_millis(500));
+            drop(stream);
+        }));
+        let proxy = Proxy::new([address]).with_timeouts(Duration::from_secs(1), Duration::from_millis(100));
+        let started = Instant::now();
+        let request = Request { method: "GET".to_string(), ..Default::default() };
+        assert_eq!(504, proxy.forward(&request, "").status);
+        assert!(started.elapsed() < Duration::from_millis(400));
+        silent.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn health_checks_mark_failing_upstreams_down() {
+        let mut router = Router::new();
+        router.get("/health", |_| Response::text(503, "busy"));
+        let address = upstream(router, 1);
+        let proxy = Proxy::new([address.to_string(), closed_port()]).with_health_check("/health", Duration::from_secs(60));
+        let started = Instant::now();
+        while proxy.upstreams.list.iter().any(|upstream| upstream.healthy.load(Ordering::Relaxed)) {
+            assert!(started.elapsed() < Duration::from_secs(5), "health check didnt run");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let raw = b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+        let mut decoded = String::new();
+        Dechunked { reader: &raw[..], decoder: Decoder::new(Framing::Chunked) }.read_to_string(&mut decoded).unwrap();
+        assert_eq!("hello, world", decoded);
+
+        let mut truncated = Dechunked { reader: &b"5\r\nhel"[..], decoder: Decoder::new(Framing::Chunked) };
+        assert!(truncated.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn normalizes_upstream_addresses() {
+        assert_eq!(Some("localhost:9000".to_string()), upstream_address("http://localhost:9000/"));
+        assert_eq!(Some("10.0.0.1:80".to_string()), upstream_address("10.0.0.1"));
+        assert_eq!(Some("[::1]:8080".to_string()), upstream_address("[::1]:8080"));
+        assert_eq!(Some("[::1]:80".to_string()), upstream_address("[::1]"));
+        assert_eq!(None, upstream_address("https://example.com"));
+        assert_eq!(None, upstream_address("example.com:http"));
+        assert_eq!(None, upstream_address(""));
+    }
+}
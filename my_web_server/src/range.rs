@@ -0,0 +1,147 @@
+/// An inclusive range of bytes, the way `Range` and `Content-Range` count them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    // the end is inclusive, so a range is never empty
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The value for `Content-Range`
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// No usable `Range` header, the whole content gets sent
+    Ignored,
+    /// Sorted, with overlapping and adjacent ranges merged
+    Satisfiable(Vec<ByteRange>),
+    /// None of the ranges are inside the content, answered with a 416
+    Unsatisfiable,
+}
+
+/// More than this and the header is ignored, lots of tiny ranges cost more than they save
+pub const MAX_RANGES: usize = 100;
+
+/// Parses a `Range` header like `bytes=0-499, 1000-, -200` against content of `length` bytes.
+///
+/// A header which doesnt parse is ignored rather than an error, like the spec asks for.
+/// Ranges reaching past the end are cut short, ones starting past it are dropped.
+pub fn parse(header: &str, length: u64) -> Ranges {
+    let Some((unit, specs)) = header.split_once('=') else { return Ranges::Ignored };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Ranges::Ignored;
+    }
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return Ranges::Ignored;
+        }
+        let Some((first, last)) = spec.split_once('-') else { return Ranges::Ignored };
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            // -N is the last N bytes
+            let Some(suffix) = parse_number(last) else { return Ranges::Ignored };
+            if suffix == 0 || length == 0 {
+                continue;
+            }
+            ByteRange { start: length.saturating_sub(suffix), end: length - 1 }
+        } else {
+            let Some(start) = parse_number(first) else { return Ranges::Ignored };
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                match parse_number(last) {
+                    Some(end) if end >= start => end,
+                    _ => return Ranges::Ignored,
+                }
+            };
+            if start >= length {
+                continue;
+            }
+            ByteRange { start, end: end.min(length - 1) }
+        };
+        ranges.push(range);
+    }
+    if count == 0 {
+        return Ranges::Ignored;
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+    Ranges::Satisfiable(coalesce(ranges))
+}
+
+fn parse_number(digits: &str) -> Option<u64> {
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// overlapping ranges would otherwise let a client ask for the same bytes over and over
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn satisfiable(header: &str, length: u64) -> Vec<(u64, u64)> {
+        match parse(header, length) {
+            Ranges::Satisfiable(ranges) => ranges.iter().map(|r| (r.start, r.end)).collect(),
+            other => panic!("{:?} for {}", other, header),
+        }
+    }
+
+    #[test]
+    fn parses_range_forms() {
+        assert_eq!(vec![(0, 499)], satisfiable("bytes=0-499", 10_000));
+        assert_eq!(vec![(9500, 9999)], satisfiable("bytes=9500-", 10_000));
+        assert_eq!(vec![(9800, 9999)], satisfiable("bytes=-200", 10_000));
+        assert_eq!(vec![(0, 9)], satisfiable("bytes=-200", 10));
+        assert_eq!(vec![(5, 9)], satisfiable("bytes=5-100", 10));
+        assert_eq!(vec![(0, 0), (5, 6)], satisfiable("bytes= 5-6 , 0-0", 10));
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(vec![(0, 20)], satisfiable("bytes=0-10, 5-20, 11-12", 100));
+        assert_eq!(vec![(0, 99)], satisfiable("bytes=0-, 0-, 0-", 100));
+    }
+
+    #[test]
+    fn ignores_what_it_cant_parse() {
+        for header in ["0-10", "items=0-10", "bytes=", "bytes=a-b", "bytes=10-5", "bytes=1", "bytes=-"] {
+            assert_eq!(Ranges::Ignored, parse(header, 100), "{}", header);
+        }
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(Ranges::Ignored, parse(&many, 100));
+    }
+
+    #[test]
+    fn unsatisfiable_past_the_end() {
+        assert_eq!(Ranges::Unsatisfiable, parse("bytes=100-200", 100));
+        assert_eq!(Ranges::Unsatisfiable, parse("bytes=-0", 100));
+        assert_eq!(Ranges::Unsatisfiable, parse("bytes=0-", 0));
+    }
+}
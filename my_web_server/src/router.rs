@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::http::{Request, Response};
+use crate::middleware::{Middleware, Next};
+use crate::websocket::{self, WebSocket, WebSocketHandler};
+
+/// Handlers are shared by all the worker threads, hence Send + Sync
+pub type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+// the method of routes added with Router::any
+const ANY: &str = "*";
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `:name`, matches exactly one non empty path segment
+    Param(String),
+    /// `*name` (or just `*`), only allowed last, matches the rest of the path
+    Wildcard(String),
+}
+
+struct Route {
+    method: String,
+    /// As it was registered, used to label the request in logs and metrics
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Handler,
+    /// Added with Router::streaming, the server leaves the body on the connection
+    streams_body: bool,
+}
+
+/// Picks a handler by method and path.
+///
+/// ```
+/// use my_web_server::http::Response;
+/// use my_web_server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.get("/users/:id", |request| Response::text(200, format!("user {}", request.param("id").unwrap())));
+/// router.get("/files/*path", |request| Response::text(200, request.param("path").unwrap().to_string()));
+/// ```
+///
+/// When several routes match, literal segments win over `:params`, which win over wildcards.
+/// HEAD requests go to the GET route unless there is a HEAD route of their own, the server
+/// leaves out the body. A path that matches a route of another method gets a 405 listing the
+/// allowed methods, anything else goes to the not found handler.
+///
+/// [`Middleware`] added with [`wrap`](Router::wrap) runs around all of that.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_| Response::text(404, "Not Found")),
+            middleware: Vec::new(),
+        }
+    }
+
+    pub fn route<F>(&mut self, method: &str, pattern: &str, handler: F) -> &mut Router
+        where
+            F: Fn(&Request) -> Response,
+            F: Send + Sync + 'static,
+    {
+        self.add(method, pattern, Box::new(handler), false)
+    }
+
+    /// Like [`route`](Router::route), but the body isnt read before the handler runs and
+    /// isnt limited by [`Limits::max_body`](crate::http::Limits::max_body). The handler reads
+    /// it off the connection with [`Request::body_reader`], for uploads and proxying which
+    /// shouldnt sit in memory whole. `*` as the method matches any, like [`any`](Router::any).
+    ///
+    /// Whether to stream is decided before the middleware runs, by the path the client sent.
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    /// use my_web_server::http::Response;
+    /// use my_web_server::router::Router;
+    ///
+    /// let mut router = Router::new();
+    /// router.streaming("POST", "/upload", |request| match request.multipart(Path::new("uploads")) {
+    ///     Ok(multipart) => Response::text(200, format!("{} files", multipart.files.len())),
+    ///     Err(e) => e.into(),
+    /// });
+    /// ```
+    pub fn streaming<F>(&mut self, method: &str, pattern: &str, handler: F) -> &mut Router
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.add(method, pattern, Box::new(handler), true)
+    }
+
+    fn add(&mut self, method: &str, pattern: &str, handler: Handler, streams_body: bool) -> &mut Router {
+        let segments = split_path(pattern)
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Wildcard(if name.is_empty() { "*".to_string() } else { name.to_string() })
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect::<Vec<_>>();
+        let wildcards = segments.iter().position(|s| matches!(s, Segment::Wildcard(_)));
+        assert!(
+            wildcards.is_none() || wildcards == Some(segments.len() - 1),
+            "wildcard must be the last segment of route {}",
+            pattern
+        );
+        self.routes.push(Route { method: method.to_ascii_uppercase(), pattern: pattern.to_string(), segments, handler, streams_body });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route("POST", pattern, handler)
+    }
+
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route("PUT", pattern, handler)
+    }
+
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// Matches the path whatever the method, a route for the method itself still wins over it
+    pub fn any<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(ANY, pattern, handler)
+    }
+
+    /// Accepts websocket connections on the path, the handler then has the connection to
+    /// itself until it returns. Requests without a valid handshake get a 400 or 426.
+    pub fn websocket<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+        where F: Fn(&Request, &mut WebSocket) + Send + Sync + 'static
+    {
+        let handler: WebSocketHandler = Arc::new(handler);
+        self.route("GET", pattern, move |request| websocket::upgrade(request, Arc::clone(&handler)))
+    }
+
+    /// Replaces the handler used when no route matches the path at all
+    pub fn not_found<F>(&mut self, handler: F) -> &mut Router
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Runs the middleware around every request, the first one added is the outermost
+    pub fn wrap<M>(&mut self, middleware: M) -> &mut Router
+        where M: Middleware + 'static
+    {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Runs the middleware and then the best matching handler, after filling in `request.params`
+    /// and the `route` extension with the pattern it was registered with
+    pub fn handle(&self, request: &mut Request) -> Response {
+        Next::new(&self.middleware, &|request| self.dispatch(request)).run(request)
+    }
+
+    // Whether the request is for a route added with Router::streaming
+    pub(crate) fn streams_body(&self, request: &Request) -> bool {
+        self.find(request).is_ok_and(|(route, _)| route.streams_body)
+    }
+
+    fn dispatch(&self, request: &mut Request) -> Response {
+        match self.find(request) {
+            Ok((route, params)) => {
+                request.params = params;
+                request.extensions.insert("route".to_string(), route.pattern.clone());
+                (route.handler)(request)
+            },
+            Err(mut allowed) if !allowed.is_empty() => {
+                if allowed.contains(&"GET") {
+                    allowed.push("HEAD");
+                }
+                allowed.sort();
+                allowed.dedup();
+                Response::text(405, "Method Not Allowed").with_header("Allow", &allowed.join(", "))
+            },
+            Err(_) => (self.not_found)(request),
+        }
+    }
+
+    // The best matching route and its params, or the methods the path has routes for
+    fn find(&self, request: &Request) -> Result<(&Route, HashMap<String, String>), Vec<&str>> {
+        let mut best: Option<(Vec<u8>, &Route, HashMap<String, String>)> = None;
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            let Some(params) = match_segments(&route.segments, &request.path) else { continue };
+            let Some(preference) = method_preference(&route.method, &request.method) else {
+                allowed.push(&route.method);
+                continue;
+            };
+            let rank = specificity(&route.segments);
+            let better = |(best_rank, best, _): &(Vec<u8>, &Route, _)| {
+                rank > *best_rank || (rank == *best_rank && preference > method_preference(&best.method, &request.method).unwrap_or(0))
+            };
+            if best.as_ref().is_none_or(better) {
+                best = Some((rank, route, params));
+            }
+        }
+        best.map(|(_, route, params)| (route, params)).ok_or(allowed)
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+// Whether a route for `method` answers the request, and if so how well it fits for breaking ties
+// between equally specific routes: the method itself, then GET for a HEAD, then any method
+fn method_preference(method: &str, requested: &str) -> Option<u8> {
+    if method == requested {
+        Some(2)
+    } else if method == "GET" && requested == "HEAD" {
+        Some(1)
+    } else if method == ANY {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+// compared lexicographically, so the first segment that differs decides
+fn specificity(segments: &[Segment]) -> Vec<u8> {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Literal(_) => 2,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 0,
+        })
+        .collect()
+}
+
+fn match_segments(segments: &[Segment], path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut parts = split_path(path);
+    for segment in segments {
+        match segment {
+            Segment::Wildcard(name) => {
+                let rest: Vec<&str> = parts.by_ref().collect();
+                params.insert(name.clone(), rest.join("/"));
+                return Some(params);
+            },
+            Segment::Literal(literal) => {
+                if parts.next()? != literal {
+                    return None;
+                }
+            },
+            Segment::Param(name) => {
+                let part = parts.next()?;
+                if part.is_empty() {
+                    return None;
+                }
+                params.insert(name.clone(), part.to_string());
+            },
+        }
+    }
+    // every part of the path has to be used up by the route
+    match parts.next() {
+        None => Some(params),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str) -> Request {
+        Request { method: method.to_string(), path: path.to_string(), ..Default::default() }
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router
+            .get("/", |_| Response::text(200, "index"))
+            .get("/users/:id", |r| Response::text(200, format!("user {}", r.param("id").unwrap())))
+            .get("/users/me", |_| Response::text(200, "me"))
+            .delete("/users/:id", |_| Response::new(204))
+            .get("/files/*path", |r| Response::text(200, format!("file {}", r.param("path").unwrap())));
+        router
+    }
+
+    #[test]
+    fn picks_the_most_specific_route() {
+        let router = router();
+        assert_eq!("index", body(router.handle(&mut request("GET", "/"))));
+        assert_eq!("user 42", body(router.handle(&mut request("GET", "/users/42"))));
+        assert_eq!("me", body(router.handle(&mut request("GET", "/users/me"))));
+        assert_eq!("file a/b.txt", body(router.handle(&mut request("GET", "/files/a/b.txt"))));
+        assert_eq!("file ", body(router.handle(&mut request("GET", "/files/"))));
+    }
+
+    #[test]
+    fn not_found_and_method_not_allowed() {
+        let router = router();
+        assert_eq!(404, router.handle(&mut request("GET", "/users")).status);
+        assert_eq!(404, router.handle(&mut request("GET", "/users/42/posts")).status);
+        let response = router.handle(&mut request("POST", "/users/42"));
+        assert_eq!(405, response.status);
+        assert_eq!(Some("DELETE, GET, HEAD"), response.header("allow"));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let mut router = router();
+        assert_eq!("user 42", body(router.handle(&mut request("HEAD", "/users/42"))));
+        assert_eq!("me", body(router.handle(&mut request("HEAD", "/users/me"))));
+        router.route("HEAD", "/users/:id", |_| Response::text(200, "head"));
+        assert_eq!("head", body(router.handle(&mut request("HEAD", "/users/42"))));
+        // a route for any method is a worse fit than the GET one
+        router.any("/files/*path", |_| Response::text(200, "any"));
+        assert_eq!("file a", body(router.handle(&mut request("HEAD", "/files/a"))));
+        assert_eq!("any", body(router.handle(&mut request("PUT", "/files/a"))));
+    }
+
+    #[test]
+    fn any_matches_every_method() {
+        let mut router = router();
+        router.any("/users/:id", |r| Response::text(200, format!("any {}", r.method)));
+        assert_eq!("any POST", body(router.handle(&mut request("POST", "/users/42"))));
+        assert_eq!("user 42", body(router.handle(&mut request("GET", "/users/42"))));
+        assert_eq!("me", body(router.handle(&mut request("GET", "/users/me"))));
+    }
+
+    #[test]
+    fn knows_which_routes_stream_their_body() {
+        let mut router = router();
+        router.streaming("POST", "/users/:id", |_| Response::new(201)).streaming("*", "/upload/*path", |_| Response::new(201));
+        assert!(router.streams_body(&request("POST", "/users/1")));
+        assert!(router.streams_body(&request("PUT", "/upload/a/b")));
+        assert!(!router.streams_body(&request("GET", "/users/1")));
+        assert!(!router.streams_body(&request("POST", "/nowhere")));
+        assert_eq!(201, router.handle(&mut request("POST", "/users/1")).status);
+    }
+}
@@ -0,0 +1,634 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::compression::Compression;
+use crate::config::Config;
+use crate::http::{self, Body, BodyStream, Framing, Limits, ParseError, Request, Response};
+use crate::log::AccessLog;
+use crate::metrics::Metrics;
+use crate::router::Router;
+use crate::{panic_message, ThreadPool};
+
+/// How long a persistent connection is kept around and how much it may be used
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    /// Close the connection when the client sends nothing for this long
+    pub idle_timeout: Duration,
+    /// Answer with a 408 when a request takes longer than this to arrive, counted from its
+    /// first byte, so trickling it in slowly doesnt tie up a worker
+    pub request_timeout: Duration,
+    /// Give up on a client which doesnt take the response off our hands for this long
+    pub write_timeout: Duration,
+    /// Sizes above which requests are answered with 414, 431 or 413
+    pub limits: Limits,
+    /// Close the connection after answering this many requests on it
+    pub max_requests: usize,
+    /// Once set, connections are closed after their current request
+    pub shutdown: Arc<AtomicBool>,
+    /// Where answered requests get logged, None to not log them
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Speak HTTPS with this instead of plain HTTP
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// Compress response bodies for clients which accept it, None sends them as they are
+    pub compression: Option<Compression>,
+    /// Close a websocket when the client sends nothing, not even a ping, for this long
+    pub websocket_idle_timeout: Duration,
+    /// Where requests and connections get counted, None to not count them
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            idle_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            limits: Limits::default(),
+            max_requests: 100,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            access_log: None,
+            tls: None,
+            compression: Some(Compression::default()),
+            websocket_idle_timeout: Duration::from_secs(300),
+            metrics: None,
+        }
+    }
+}
+
+/// A flag which gets set by SIGINT or SIGTERM. A second signal after that exits straight away,
+/// for when a graceful shutdown is taking too long.
+pub fn shutdown_signal() -> io::Result<Arc<AtomicBool>> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    let flag = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        // registered first so it sees the flag from before the second signal sets it again
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&flag))?;
+        signal_hook::flag::register(signal, Arc::clone(&flag))?;
+    }
+    Ok(flag)
+}
+
+/// Accepts connections and hands them to a pool of `config.workers` threads until
+/// `config.max_connections` is reached or `config.connection.shutdown` is set.
+///
+/// Then it stops accepting and gives the requests in flight `config.shutdown_timeout` to
+/// finish, returns false if they didnt make it in time.
+pub fn serve(listener: TcpListener, config: &Config, router: Arc<Router>) -> io::Result<bool> {
+    let thread_pool = ThreadPool::new(config.workers);
+    if let Some(metrics) = &config.connection.metrics {
+        metrics.watch_pool(thread_pool.stats());
+    }
+    let shutdown = Arc::clone(&config.connection.shutdown);
+    // accept would block until the next client comes along and never see the flag,
+    // so poll instead
+    listener.set_nonblocking(true)?;
+    let mut accepted = 0;
+    while !shutdown.load(Ordering::SeqCst) && config.max_connections.is_none_or(|max| accepted < max) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            },
+            // e.g. the client gave up before we got to it, or we ran out of file descriptors,
+            // neither is a reason to stop serving everybody else
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            },
+        };
+        // on some platforms accepted sockets inherit the listener's non blocking mode
+        if let Err(e) = stream.set_nonblocking(false) {
+            warn!("Failed to set up connection: {}", e);
+            continue;
+        }
+        accepted += 1;
+        let router = Arc::clone(&router);
+        let options = config.connection.clone();
+        thread_pool.execute(move || {
+            handle_connection(stream, &router, &options);
+        });
+    }
+    info!("Shutting down");
+    Ok(thread_pool.shutdown(config.shutdown_timeout))
+}
+
+/// Answers requests on the connection until either side wants to close it, over TLS when
+/// `options.tls` is set.
+///
+/// HTTP/1.1 connections are kept alive unless the client sends `Connection: close`,
+/// HTTP/1.0 ones only when the client asks for `Connection: keep-alive`. Pipelined requests
+/// are answered in order, their responses are only flushed once no more requests are buffered.
+pub fn handle_connection(stream: TcpStream, router: &Router, options: &ConnectionOptions) {
+    // set on the socket itself, so they apply to the TLS handshake as well
+    let timeouts = stream.set_read_timeout(Some(options.idle_timeout))
+        .and_then(|_| stream.set_write_timeout(Some(options.write_timeout)));
+    if let Err(e) = timeouts {
+        warn!("Failed to set timeouts: {}", e);
+        return;
+    }
+    // for changing the read timeout while the stream itself is wrapped up in TLS
+    let socket = match stream.try_clone() {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Failed to set up connection: {}", e);
+            return;
+        },
+    };
+    let client = stream.peer_addr().ok();
+    debug!("Connection established with {:?}", client);
+    let _open = options.metrics.as_ref().map(|metrics| metrics.connection_opened());
+    match &options.tls {
+        Some(tls) => {
+            let connection = match rustls::ServerConnection::new(Arc::clone(tls)) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to set up TLS for {:?}: {}", client, e);
+                    return;
+                },
+            };
+            let mut stream = answer_requests(rustls::StreamOwned::new(connection, stream), socket, client, router, options);
+            // lets the client tell a complete response from a truncated one, and sends the
+            // alert after a failed handshake. Not through flush, that would try to finish
+            // the handshake first.
+            stream.conn.send_close_notify();
+            while stream.conn.wants_write() {
+                if stream.conn.write_tls(&mut stream.sock).is_err() {
+                    break;
+                }
+            }
+        },
+        None => {
+            answer_requests(stream, socket, client, router, options);
+        },
+    }
+}
+
+// Returns the stream once the connection is done with, for closing it
+fn answer_requests<S: Read + Write + Send + 'static>(
+    stream: S,
+    socket: TcpStream,
+    client: Option<SocketAddr>,
+    router: &Router,
+    options: &ConnectionOptions,
+) -> S {
+    let stream = Deadline { inner: stream, socket, idle_timeout: options.idle_timeout, deadline: None };
+    let mut buf_reader = BufReader::new(stream);
+    // responses which havent been flushed yet
+    let mut pending = Vec::new();
+
+    for served in 1.. {
+        // waiting for the next request only counts against the idle timeout, the request
+        // timeout starts with its first byte
+        buf_reader.get_mut().deadline = None;
+        match buf_reader.fill_buf() {
+            Ok([]) => break,
+            Ok(_) => {},
+            Err(e) if is_timeout(&e) => break,
+            Err(e) => {
+                debug!("Failed to read request from {:?}: {}", client, e);
+                break;
+            },
+        }
+        buf_reader.get_mut().deadline = Some(Instant::now() + options.request_timeout);
+        let result = Request::read_head(&mut buf_reader, &options.limits).and_then(|mut request| {
+            if router.streams_body(&request) {
+                let framing = http::framing(&request)?;
+                return Ok((request, Some(framing)));
+            }
+            request.body = http::read_body(&mut buf_reader, &request, &options.limits)?;
+            Ok((request, None))
+        });
+        buf_reader.get_mut().deadline = None;
+        let (mut request, streamed_body) = match result {
+            Ok(read) => read,
+            Err(ParseError::ConnectionClosed) => break,
+            Err(e) => {
+                debug!("Failed to read request from {:?}: {}", client, e);
+                if let Some(mut response) = error_response(&e) {
+                    let _ = response.write_to(&mut pending);
+                    let _ = flush(buf_reader.get_mut(), &mut pending);
+                }
+                break;
+            }
+        };
+        let started = Instant::now();
+
+        let (mut response, keep_alive) = match streamed_body {
+            None => respond(router, &mut request, client, options, served),
+            Some(framing) => {
+                let (response, keep_alive, reader) = respond_streaming(router, &mut request, client, options, served, buf_reader, framing);
+                buf_reader = reader;
+                (response, keep_alive)
+            },
+        };
+        let upgrade = response.upgrade.take();
+        let written = if !matches!(response.body, Body::Stream { .. }) {
+            response.write_to(&mut pending).and_then(|sent| {
+                if !keep_alive || upgrade.is_some() || buf_reader.buffer().is_empty() {
+                    flush(buf_reader.get_mut(), &mut pending)?;
+                }
+                Ok(sent)
+            })
+        } else {
+            // holding a streamed body back for pipelining would mean holding all of it
+            flush(buf_reader.get_mut(), &mut pending).and_then(|_| {
+                let mut writer = BufWriter::new(buf_reader.get_mut());
+                let sent = response.write_to(&mut writer)?;
+                writer.flush()?;
+                Ok(sent)
+            })
+        };
+        record(options, client, &request, &response, *written.as_ref().unwrap_or(&0), started);
+        // most likely the client went away, so there is nobody left to tell
+        if let Err(e) = written {
+            debug!("Failed to write response to {:?}: {}", client, e);
+            break;
+        }
+        if let Some(upgrade) = upgrade {
+            // the connection belongs to the new protocol now, whatever the client sent after
+            // the handshake is still in the buffer for it
+            buf_reader.get_mut().idle_timeout = options.websocket_idle_timeout;
+            let mut upgraded = Upgraded(&mut buf_reader);
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| upgrade.run(&mut upgraded))) {
+                error!("Upgrade for {} {} panicked: {}", request.method, request.path, panic_message(payload.as_ref()));
+            }
+            break;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+    buf_reader.into_inner().inner
+}
+
+/// Runs the handler for the request and gets the response ready for sending, the bool says
+/// whether the connection stays open after it. Shared by both servers.
+///
+/// The client's IP address goes into the `client` extension for the handler.
+pub(crate) fn respond(
+    router: &Router,
+    request: &mut Request,
+    client: Option<SocketAddr>,
+    options: &ConnectionOptions,
+    served: usize,
+) -> (Response, bool) {
+    if let Some(client) = client {
+        request.extensions.insert("client".to_string(), client.ip().to_string());
+    }
+    // a panicking handler only costs this one request a 500
+    let mut response = match panic::catch_unwind(AssertUnwindSafe(|| router.handle(request))) {
+        Ok(response) => response,
+        Err(payload) => {
+            error!("Handler for {} {} panicked: {}", request.method, request.path, panic_message(payload.as_ref()));
+            Response::text(500, "Internal Server Error").with_header("Connection", "close")
+        },
+    };
+    if let Some(compression) = &options.compression {
+        response = compression.apply(request, response);
+    }
+    // the same headers a GET would get, the body is dropped unread
+    if request.method == "HEAD" {
+        let length = response.body.len();
+        response.body = Body::Omitted { length };
+    }
+    // HTTP/1.0 has no chunked encoding, so the length has to be known before sending
+    let streamed = matches!(response.body, Body::Stream { .. });
+    if request.version == "HTTP/1.0" && streamed && response.body.len().is_none() {
+        let body = mem::replace(&mut response.body, Body::Full(Vec::new()));
+        match body.into_bytes() {
+            Ok(bytes) => response.body = Body::Full(bytes),
+            Err(e) => {
+                error!("Failed to read response body for {} {}: {}", request.method, request.path, e);
+                response = Response::text(500, "Internal Server Error");
+            },
+        }
+    }
+    let keep_alive = served < options.max_requests
+        && !options.shutdown.load(Ordering::SeqCst)
+        && wants_keep_alive(request)
+        && !has_token(response.header("connection"), "close");
+    if response.header("connection").is_none() {
+        response = response.with_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+    }
+    (response, keep_alive)
+}
+
+/// Like [`respond`], with the body left on `reader` for the handler to read. The reader comes
+/// back past whatever of the body the handler didnt read, if that could be skipped.
+pub(crate) fn respond_streaming<R: BufRead + Send + 'static>(
+    router: &Router,
+    request: &mut Request,
+    client: Option<SocketAddr>,
+    options: &ConnectionOptions,
+    served: usize,
+    reader: R,
+    framing: Framing,
+) -> (Response, bool, R) {
+    let (stream, lent) = BodyStream::lend(reader, framing);
+    request.body_stream = Some(stream);
+    let (mut response, keep_alive) = respond(router, request, client, options, served);
+    let (reader, finished) = lent.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).give_back();
+    if keep_alive && !finished {
+        // the next request would start somewhere in the middle of this one's body
+        response.set_header("Connection", "close");
+        return (response, false, reader);
+    }
+    (response, keep_alive, reader)
+}
+
+/// What to answer a request which couldnt be read with, if anything
+pub(crate) fn error_response(e: &ParseError) -> Option<Response> {
+    let status = match e {
+        ParseError::Io(io) if is_timeout(io) => Some(408),
+        // for some errors there is nobody to answer to
+        e => e.status(),
+    };
+    status.map(|status| Response::text(status, e.to_string()).with_header("Connection", "close"))
+}
+
+pub(crate) fn record(
+    options: &ConnectionOptions,
+    client: Option<SocketAddr>,
+    request: &Request,
+    response: &Response,
+    sent: u64,
+    started: Instant,
+) {
+    if let Some(access_log) = &options.access_log {
+        access_log.record(client, request, response, sent, started.elapsed());
+    }
+    if let Some(metrics) = &options.metrics {
+        metrics.record(request, response, started.elapsed());
+    }
+}
+
+// Puts a deadline on reading a whole request on top of the idle timeout for every read
+struct Deadline<S> {
+    inner: S,
+    socket: TcpStream,
+    idle_timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+impl<S: Read> Read for Deadline<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            None => self.idle_timeout,
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long to arrive"));
+                }
+                left.min(self.idle_timeout)
+            },
+        };
+        self.socket.set_read_timeout(Some(timeout))?;
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for Deadline<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Reads through the buffer, which may already hold the first frames, and writes straight out
+struct Upgraded<'a, R>(&'a mut BufReader<R>);
+
+impl<R: Read> Read for Upgraded<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Write> Write for Upgraded<'_, R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+fn flush(stream: &mut impl Write, pending: &mut Vec<u8>) -> io::Result<()> {
+    let written = stream.write_all(pending).and_then(|_| stream.flush());
+    pending.clear();
+    written
+}
+
+fn wants_keep_alive(request: &Request) -> bool {
+    let connection = request.header("connection");
+    if request.version == "HTTP/1.0" {
+        has_token(connection, "keep-alive")
+    } else {
+        !has_token(connection, "close")
+    }
+}
+
+// Connection is a comma separated list of case insensitive tokens
+fn has_token(header: Option<&str>, token: &str) -> bool {
+    header.is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+// read timeouts show up as WouldBlock on unix and TimedOut on windows
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // serves one connection in the background, like a ThreadPool worker would
+    fn serve_one(options: ConnectionOptions) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut router = Router::new();
+            router.get("/:name", |r| Response::text(200, r.param("name").unwrap().to_string()));
+            router.get("/stream/:n", |r| {
+                let n: usize = r.param("n").unwrap().parse().unwrap();
+                Response::new(200).with_chunks((0..n).map(|i| format!("line {}\n", i).into_bytes()))
+            });
+            router.streaming("POST", "/count", |r| Response::text(200, io::copy(&mut r.body_reader(), &mut io::sink()).unwrap().to_string()));
+            router.streaming("POST", "/ignore", |_| Response::new(204));
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &router, &options);
+        });
+        TcpStream::connect(address).unwrap()
+    }
+
+    #[test]
+    fn pipelined_requests_on_one_connection() {
+        let mut client = serve_one(ConnectionOptions::default());
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        assert_eq!(3, responses.matches("HTTP/1.1 200 OK").count());
+        assert_eq!(2, responses.matches("Connection: keep-alive").count());
+        assert!(responses.ends_with("Connection: close\r\nContent-Length: 1\r\n\r\nc"));
+    }
+
+    #[test]
+    fn closes_after_max_requests() {
+        let mut client = serve_one(ConnectionOptions { max_requests: 2, ..Default::default() });
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n").unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        assert_eq!(2, responses.matches("HTTP/1.1 200 OK").count());
+    }
+
+    #[test]
+    fn http_1_0_closes_by_default() {
+        let mut client = serve_one(ConnectionOptions::default());
+        client.write_all(b"GET /a HTTP/1.0\r\n\r\n").unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        assert!(responses.contains("Connection: close"));
+    }
+
+    #[test]
+    fn streams_chunked_and_buffers_for_http_1_0() {
+        let mut client = serve_one(ConnectionOptions::default());
+        client.write_all(b"GET /stream/2 HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        assert!(responses.contains("Transfer-Encoding: chunked\r\n\r\n7\r\nline 0\n\r\n7\r\nline 1\n\r\n0\r\n\r\nHTTP/1.1 200 OK"));
+
+        let mut client = serve_one(ConnectionOptions::default());
+        client.write_all(b"GET /stream/2 HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("Content-Length: 14\r\n\r\nline 0\nline 1\n"));
+    }
+
+    #[test]
+    fn head_responses_have_the_length_but_no_body() {
+        let mut client = serve_one(ConnectionOptions::default());
+        client.write_all(b"HEAD /abc HTTP/1.1\r\n\r\nHEAD /stream/2 HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        let responses: Vec<&str> = responses.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(3, responses.len());
+        assert!(responses[0].ends_with("Content-Length: 3\r\n\r\n"), "{}", responses[0]);
+        assert!(responses[1].ends_with("keep-alive\r\n\r\n"), "{}", responses[1]);
+        assert!(responses[2].ends_with("Content-Length: 1\r\n\r\na"));
+    }
+
+    #[test]
+    fn streaming_routes_read_the_body_themselves() {
+        let limits = Limits { max_body: 10, ..Default::default() };
+        let mut client = serve_one(ConnectionOptions { limits, ..Default::default() });
+        let mut writer = client.try_clone().unwrap();
+        thread::spawn(move || {
+            writer.write_all(b"POST /count HTTP/1.1\r\nContent-Length: 100000\r\n\r\n").unwrap();
+            writer.write_all(&[b'x'; 100_000]).unwrap();
+            writer.write_all(b"POST /count HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n").unwrap();
+            // a little unread body is skipped, more than that closes the connection
+            writer.write_all(b"POST /ignore HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+            writer.write_all(b"POST /ignore HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n").unwrap();
+            let _ = writer.write_all(&vec![b'x'; 1_000_000]);
+        });
+        let mut responses = String::new();
+        let _ = client.read_to_string(&mut responses);
+        let responses: Vec<&str> = responses.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(4, responses.len(), "{:?}", responses);
+        assert!(responses[0].ends_with("keep-alive\r\nContent-Length: 6\r\n\r\n100000"), "{}", responses[0]);
+        assert!(responses[1].ends_with("\r\n\r\n12"), "{}", responses[1]);
+        assert!(responses[2].starts_with("204") && responses[2].contains("keep-alive"), "{}", responses[2]);
+        assert!(responses[3].starts_with("204") && responses[3].contains("Connection: close"), "{}", responses[3]);
+    }
+
+    #[test]
+    fn slow_requests_get_a_408() {
+        let options = ConnectionOptions { request_timeout: Duration::from_millis(200), ..Default::default() };
+        let mut client = serve_one(options);
+        let mut writer = client.try_clone().unwrap();
+        // a byte every 50ms never runs into the idle timeout, only into the request timeout
+        thread::spawn(move || {
+            for byte in b"GET /a HTTP/1.1\r\nX-Slow: ".iter().chain([b'x'; 40].iter()) {
+                if writer.write_all(&[*byte]).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"), "{}", response);
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let mut client = serve_one(ConnectionOptions::default());
+        client.write_all(b"POST /a HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large"));
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let mut client = serve_one(ConnectionOptions { idle_timeout: Duration::from_millis(50), ..Default::default() });
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        assert_eq!("", responses);
+    }
+
+    #[test]
+    fn serve_finishes_requests_in_flight_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = Config { workers: 1, ..Default::default() };
+        let shutdown = Arc::clone(&config.connection.shutdown);
+        let mut router = Router::new();
+        router.get("/slow", |_| {
+            thread::sleep(Duration::from_millis(200));
+            Response::text(200, "done")
+        });
+        let server = thread::spawn(move || serve(listener, &config, Arc::new(router)).unwrap());
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        shutdown.store(true, Ordering::SeqCst);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.contains("Connection: close"));
+        assert!(response.ends_with("done"));
+        assert!(server.join().unwrap());
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn panicking_handler_gets_a_500() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut router = Router::new();
+            router.get("/boom", |_| panic!("boom"));
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &router, &ConnectionOptions::default());
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /boom HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error"));
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::http::{percent_decode, Request, Response};
+use crate::range::{self, ByteRange, Ranges};
+use crate::template::{Context, Templates};
+
+/// Serves the files under a document root.
+///
+/// ```no_run
+/// use my_web_server::router::Router;
+/// use my_web_server::static_files::StaticFiles;
+///
+/// let files = StaticFiles::new("public");
+/// let mut router = Router::new();
+/// router.get("/static/*path", move |request| files.serve(request, request.param("path").unwrap_or("")));
+/// ```
+pub struct StaticFiles {
+    root: PathBuf,
+    /// Tried in order when a directory is requested
+    index_files: Vec<String>,
+    /// Renders directories without an index file, they are a 404 otherwise
+    listing: Option<(Arc<Templates>, String)>,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            index_files: vec!["index.html".to_string(), "index.htm".to_string()],
+            listing: None,
+        }
+    }
+
+    pub fn with_index_files(mut self, index_files: &[&str]) -> StaticFiles {
+        self.index_files = index_files.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    /// Lists directories which have no index file with the template, it gets `path` and
+    /// `entries`, each with a `name`, `href`, `dir` and `size`
+    pub fn with_listing(mut self, templates: Arc<Templates>, template: &str) -> StaticFiles {
+        self.listing = Some((templates, template.to_string()));
+        self
+    }
+
+    /// Responds with the file at `relative_path` (still percent encoded) under the root
+    pub fn serve(&self, request: &Request, relative_path: &str) -> Response {
+        let Some(mut path) = self.resolve(relative_path) else {
+            return Response::text(404, "Not Found");
+        };
+
+        if path.is_dir() {
+            // without the slash, relative links in the index page would point at the parent
+            if !request.path.ends_with('/') {
+                let location = match &request.query {
+                    Some(query) => format!("{}/?{}", request.path, query),
+                    None => format!("{}/", request.path),
+                };
+                return Response::new(301).with_header("Location", &location);
+            }
+            match self.index_files.iter().map(|name| path.join(name)).find(|index| index.is_file()) {
+                Some(index) => path = index,
+                None => return match &self.listing {
+                    Some((templates, template)) => list_directory(request, &path, templates, template),
+                    None => Response::text(404, "Not Found"),
+                },
+            }
+        }
+
+        match serve_file(request, &path) {
+            Ok(response) => response,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Response::text(404, "Not Found"),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Response::text(403, "Forbidden"),
+            Err(e) => {
+                error!("Failed to read {}: {}", path.display(), e);
+                Response::text(500, "Internal Server Error")
+            },
+        }
+    }
+
+    /// Maps the url path onto the file system, None if it would end up outside of the root
+    fn resolve(&self, relative_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(relative_path)?;
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return None,
+                // a backslash is a separator on windows, a nul byte is never valid
+                s if s.contains(['\\', '\0']) => return None,
+                s => path.push(s),
+            }
+        }
+        // symlinks inside the root could still point outside of it
+        let root = self.root.canonicalize().ok()?;
+        let canonical = path.canonicalize().ok()?;
+        if canonical.starts_with(&root) { Some(canonical) } else { None }
+    }
+}
+
+/// Bigger files are streamed from disk instead of being read into memory first, compression
+/// then happens as they are sent
+pub const STREAM_THRESHOLD: u64 = 1024 * 1024;
+
+fn serve_file(request: &Request, path: &Path) -> io::Result<Response> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let etag = etag(metadata.len(), modified);
+    let last_modified = httpdate::fmt_http_date(modified);
+
+    let len = metadata.len();
+    let ranges = match request.header("range") {
+        Some(range) if if_range_matches(request, &etag, modified) => range::parse(range, len),
+        _ => Ranges::Ignored,
+    };
+
+    let response = if is_not_modified(request, &etag, modified) {
+        Response::new(304)
+    } else {
+        match ranges {
+            Ranges::Ignored => {
+                let response = Response::new(200).with_header("Content-Type", content_type(path));
+                if len > STREAM_THRESHOLD {
+                    response.with_reader(File::open(path)?, Some(len))
+                } else {
+                    response.with_body(fs::read(path)?)
+                }
+            },
+            Ranges::Unsatisfiable => {
+                Response::text(416, "Range Not Satisfiable").with_header("Content-Range", &format!("bytes */{}", len))
+            },
+            Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+                Response::new(206)
+                    .with_header("Content-Type", content_type(path))
+                    .with_header("Content-Range", &ranges[0].content_range(len))
+                    .with_reader(section(path, ranges[0])?, Some(ranges[0].len()))
+            },
+            Ranges::Satisfiable(ranges) => multipart_ranges(path, &ranges, len)?,
+        }
+    };
+    Ok(response
+        .with_header("Accept-Ranges", "bytes")
+        .with_header("ETag", &etag)
+        .with_header("Last-Modified", &last_modified))
+}
+
+// Renders the listing template for a directory without an index file
+fn list_directory(request: &Request, dir: &Path, templates: &Templates, template: &str) -> Response {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to list {}: {}", dir.display(), e);
+            return Response::text(500, "Internal Server Error");
+        },
+    };
+    let mut entries = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let metadata = entry.metadata().ok()?;
+            Some((name, metadata))
+        })
+        // hidden files stay hidden, they can still be fetched by name though
+        .filter(|(name, _)| !name.starts_with('.'))
+        .collect::<Vec<_>>();
+    // directories first, then by name
+    entries.sort_by(|(a, a_meta), (b, b_meta)| b_meta.is_dir().cmp(&a_meta.is_dir()).then_with(|| a.cmp(b)));
+    let entries = entries.into_iter()
+        .map(|(name, metadata)| {
+            let href = percent_encode(&name) + if metadata.is_dir() { "/" } else { "" };
+            Context::new()
+                .with("name", name)
+                .with("href", href)
+                .with("dir", metadata.is_dir())
+                .with("size", if metadata.is_dir() { None } else { Some(metadata.len()) })
+        })
+        .collect::<Vec<_>>();
+    let context = Context::new().with("path", request.path.as_str()).with("entries", entries);
+    templates.page(200, template, &context)
+}
+
+// for one path segment, anything but unreserved characters
+fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// The range of the file, read from disk as it is sent
+fn section(path: &Path, range: ByteRange) -> io::Result<io::Take<File>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    Ok(file.take(range.len()))
+}
+
+// One part per range, each with its own Content-Range
+fn multipart_ranges(path: &Path, ranges: &[ByteRange], total: u64) -> io::Result<Response> {
+    // only has to be unlikely to show up in the file, it doesnt need to be unpredictable
+    let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let content_type = content_type(path);
+    let mut body: Box<dyn Read + Send> = Box::new(io::empty());
+    let mut length = 0;
+    for &range in ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary, content_type, range.content_range(total),
+        );
+        length += head.len() as u64 + range.len();
+        body = Box::new(body.chain(io::Cursor::new(head)).chain(section(path, range)?));
+    }
+    let end = format!("\r\n--{}--\r\n", boundary);
+    length += end.len() as u64;
+    body = Box::new(body.chain(io::Cursor::new(end)));
+    Ok(Response::new(206)
+        .with_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))
+        .with_reader(body, Some(length)))
+}
+
+// Ranges only apply if the file is still the version the client has the rest of, which needs
+// a strong validator
+fn if_range_matches(request: &Request, etag: &str, modified: SystemTime) -> bool {
+    match request.header("if-range").map(|value| value.trim()) {
+        None => true,
+        Some(tag) if tag.starts_with('"') => tag == etag,
+        Some(tag) if tag.starts_with("W/") => false,
+        Some(date) => httpdate::parse_http_date(date).is_ok_and(|date| {
+            httpdate::fmt_http_date(date) == httpdate::fmt_http_date(modified)
+        }),
+    }
+}
+
+// changes whenever the file is written to, without having to hash the contents
+fn etag(len: u64, modified: SystemTime) -> String {
+    let nanos = modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    format!("\"{:x}-{:x}\"", len, nanos)
+}
+
+fn is_not_modified(request: &Request, etag: &str, modified: SystemTime) -> bool {
+    // If-None-Match wins when both are sent, and uses the weak comparison
+    if let Some(if_none_match) = request.header("if-none-match") {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    match request.header("if-modified-since").and_then(|date| httpdate::parse_http_date(date).ok()) {
+        // http dates only have whole seconds
+        Some(since) => modified.duration_since(since).map_or(true, |newer_by| newer_by.as_secs() == 0),
+        None => false,
+    }
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::test_dir::TestDir;
+
+    fn document_root(name: &str) -> TestDir {
+        let root = TestDir::new(name);
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("hello.html"), "<h1>Hello!</h1>").unwrap();
+        fs::write(root.join("docs/index.html"), "docs").unwrap();
+        fs::write(root.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0xff]).unwrap();
+        root
+    }
+
+    fn get(path: &str) -> Request {
+        Request { method: "GET".to_string(), path: path.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn serves_files_with_their_type() {
+        let root = document_root("types");
+        let files = StaticFiles::new(&*root);
+        let response = files.serve(&get("/hello.html"), "hello.html");
+        assert_eq!(200, response.status);
+        assert_eq!(Some("text/html; charset=utf-8"), response.header("content-type"));
+        let response = files.serve(&get("/logo.png"), "logo.png");
+        assert_eq!(Some("image/png"), response.header("content-type"));
+        assert_eq!(Some(&[0x89, b'P', b'N', b'G', 0, 0xff][..]), response.body.as_bytes());
+    }
+
+    #[test]
+    fn directories_use_index_files() {
+        let root = document_root("index");
+        let files = StaticFiles::new(&*root);
+        let response = files.serve(&get("/docs"), "docs");
+        assert_eq!(301, response.status);
+        assert_eq!(Some("/docs/"), response.header("location"));
+        assert_eq!(Some(&b"docs"[..]), files.serve(&get("/docs/"), "docs/").body.as_bytes());
+    }
+
+    #[test]
+    fn lists_directories_without_an_index() {
+        let root = document_root("listing");
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("a b.txt"), "hi").unwrap();
+        fs::write(root.join(".secret"), "").unwrap();
+        let templates = Templates::new(root.join("no-templates-here"));
+        templates.add("listing.html", "{{ path }}:{% for e in entries %} <a href=\"{{ e.href }}\">{{ e.name }}</a>{{ e.size }}\
+            {% else %} nothing{% endfor %}").unwrap();
+        let files = StaticFiles::new(&*root).with_listing(Arc::new(templates), "listing.html");
+        let response = files.serve(&get("/static/"), "");
+        assert_eq!(
+            "/static/: <a href=\"docs/\">docs</a> <a href=\"empty/\">empty</a> <a href=\"a%20b.txt\">a b.txt</a>2 \
+                <a href=\"hello.html\">hello.html</a>15 <a href=\"logo.png\">logo.png</a>6",
+            String::from_utf8_lossy(response.body.as_bytes().unwrap())
+        );
+        assert_eq!(Some(&b"/static/empty/: nothing"[..]), files.serve(&get("/static/empty/"), "empty/").body.as_bytes());
+        // index files still win
+        assert_eq!(Some(&b"docs"[..]), files.serve(&get("/docs/"), "docs/").body.as_bytes());
+    }
+
+    #[test]
+    fn stays_inside_the_root() {
+        let root = document_root("traversal");
+        let files = StaticFiles::new(root.join("docs"));
+        for path in ["../hello.html", "%2e%2e/hello.html", "..%2Fhello.html", "a/../../hello.html"] {
+            assert_eq!(404, files.serve(&get(path), path).status, "{}", path);
+        }
+    }
+
+    #[test]
+    fn conditional_get() {
+        let root = document_root("conditional");
+        let files = StaticFiles::new(&*root);
+        let response = files.serve(&get("/hello.html"), "hello.html");
+        let etag = response.header("etag").unwrap().to_string();
+        let last_modified = response.header("last-modified").unwrap().to_string();
+
+        let mut request = get("/hello.html");
+        request.headers.insert("if-none-match".to_string(), format!("\"other\", W/{}", etag));
+        assert_eq!(304, files.serve(&request, "hello.html").status);
+
+        let mut request = get("/hello.html");
+        request.headers.insert("if-modified-since".to_string(), last_modified);
+        let response = files.serve(&request, "hello.html");
+        assert_eq!(304, response.status);
+        assert!(response.body.is_empty());
+
+        let mut request = get("/hello.html");
+        request.headers.insert("if-modified-since".to_string(), "Sun, 06 Nov 1994 08:49:37 GMT".to_string());
+        assert_eq!(200, files.serve(&request, "hello.html").status);
+    }
+
+    fn ranged(files: &StaticFiles, range: &str, if_range: Option<&str>) -> Response {
+        let mut request = get("/digits.txt");
+        request.headers.insert("range".to_string(), range.to_string());
+        if let Some(if_range) = if_range {
+            request.headers.insert("if-range".to_string(), if_range.to_string());
+        }
+        files.serve(&request, "digits.txt")
+    }
+
+    #[test]
+    fn serves_byte_ranges() {
+        let root = document_root("ranges");
+        fs::write(root.join("digits.txt"), "0123456789").unwrap();
+        let files = StaticFiles::new(&*root);
+
+        let response = ranged(&files, "bytes=2-4", None);
+        assert_eq!(206, response.status);
+        assert_eq!(Some("bytes 2-4/10"), response.header("content-range"));
+        assert_eq!(b"234".to_vec(), response.body.into_bytes().unwrap());
+
+        let response = ranged(&files, "bytes=0-0,-2", None);
+        assert_eq!(206, response.status);
+        let content_type = response.header("content-type").unwrap().to_string();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-0/10\r\n\r\n0\
+             \r\n--{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{b}--\r\n",
+            b = boundary,
+        );
+        assert_eq!(Some(expected.len() as u64), response.body.len());
+        assert_eq!(expected.into_bytes(), response.body.into_bytes().unwrap());
+
+        let response = ranged(&files, "bytes=10-", None);
+        assert_eq!(416, response.status);
+        assert_eq!(Some("bytes */10"), response.header("content-range"));
+
+        assert_eq!(200, ranged(&files, "lines=1-2", None).status);
+    }
+
+    #[test]
+    fn if_range_needs_the_current_version() {
+        let root = document_root("if-range");
+        fs::write(root.join("digits.txt"), "0123456789").unwrap();
+        let files = StaticFiles::new(&*root);
+        let response = files.serve(&get("/digits.txt"), "digits.txt");
+        assert_eq!(Some("bytes"), response.header("accept-ranges"));
+        let etag = response.header("etag").unwrap().to_string();
+        let last_modified = response.header("last-modified").unwrap().to_string();
+
+        assert_eq!(206, ranged(&files, "bytes=0-1", Some(&etag)).status);
+        assert_eq!(206, ranged(&files, "bytes=0-1", Some(&last_modified)).status);
+        assert_eq!(200, ranged(&files, "bytes=0-1", Some("\"stale\"")).status);
+        assert_eq!(200, ranged(&files, "bytes=0-1", Some(&format!("W/{}", etag))).status);
+        assert_eq!(200, ranged(&files, "bytes=0-1", Some("Sun, 06 Nov 1994 08:49:37 GMT")).status);
+    }
+
+    #[test]
+    fn streams_big_files() {
+        let root = document_root("big");
+        fs::write(root.join("big.bin"), vec![7; STREAM_THRESHOLD as usize + 1]).unwrap();
+        let response = StaticFiles::new(&*root).serve(&get("/big.bin"), "big.bin");
+        assert!(response.body.as_bytes().is_none());
+        assert_eq!(Some(STREAM_THRESHOLD + 1), response.body.len());
+        assert_eq!(vec![7; STREAM_THRESHOLD as usize + 1], response.body.into_bytes().unwrap());
+    }
+
+    #[test]
+    fn compresses_big_text_files_while_streaming() {
+        let root = document_root("big-text");
+        let css = "body { margin: 0 }\n".repeat(STREAM_THRESHOLD as usize / 10);
+        fs::write(root.join("big.css"), &css).unwrap();
+        let mut request = get("/big.css");
+        request.headers.insert("accept-encoding".to_string(), "gzip".to_string());
+        let response = StaticFiles::new(&*root).serve(&request, "big.css");
+        assert!(response.body.as_bytes().is_none());
+        let etag = response.header("etag").unwrap().to_string();
+
+        let response = Compression::default().apply(&request, response);
+        assert_eq!(Some("gzip"), response.header("content-encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("vary"));
+        assert_eq!(Some(format!("W/{}", etag).as_str()), response.header("etag"));
+        assert_eq!(None, response.body.len());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&response.body.into_bytes().unwrap()[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(css, decoded);
+    }
+}
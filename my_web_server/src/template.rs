@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::http::Response;
+
+/// Renders HTML templates from a directory, each one is parsed once and then cached.
+///
+/// The syntax is a small subset of what jinja and friends do:
+///
+/// - `{{ user.name }}` inserts a value HTML escaped, `{{ body | raw }}` inserts it as it is
+/// - `{% if user %}...{% else %}...{% endif %}`, also `{% if not user %}`
+/// - `{% for file in files %}...{% else %}shown for an empty list{% endfor %}`
+/// - `{% include "header.html" %}` renders another template with the same values
+/// - `{# comments #}` are dropped
+///
+/// A newline right after a `{% %}` tag or a comment is dropped along with it.
+/// Missing values render as nothing and count as false, like an empty string or list.
+///
+/// ```no_run
+/// use my_web_server::router::Router;
+/// use my_web_server::template::{Context, Templates};
+///
+/// let templates = Templates::new("templates");
+/// let mut router = Router::new();
+/// router.not_found(move |request| {
+///     templates.page(404, "404.html", &Context::new().with("path", request.path.as_str()))
+/// });
+/// ```
+pub struct Templates {
+    dir: PathBuf,
+    cache: RwLock<HashMap<String, Arc<Template>>>,
+}
+
+/// Includes nested deeper than this are taken to be a template including itself
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
+impl Templates {
+    pub fn new(dir: impl Into<PathBuf>) -> Templates {
+        Templates { dir: dir.into(), cache: RwLock::new(HashMap::new()) }
+    }
+
+    /// Adds a template which doesnt live in the directory, or replaces a cached one
+    pub fn add(&self, name: &str, source: &str) -> Result<(), TemplateError> {
+        let template = Template::parse(name, source)?;
+        self.cache.write().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(name.to_string(), Arc::new(template));
+        Ok(())
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut scope = Scope { context, locals: Vec::new() };
+        self.render_template(name, &mut scope, &mut out, 0)?;
+        Ok(out)
+    }
+
+    /// Renders an html response, or logs what went wrong and answers with a 500
+    pub fn page(&self, status: u16, name: &str, context: &Context) -> Response {
+        match self.render(name, context) {
+            Ok(html) => Response::html(status, html),
+            Err(e) => {
+                error!("Failed to render {}: {}", name, e);
+                Response::text(500, "Internal Server Error")
+            },
+        }
+    }
+
+    fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        if let Some(template) = self.cache.read().unwrap_or_else(|poisoned| poisoned.into_inner()).get(name) {
+            return Ok(Arc::clone(template));
+        }
+        // names come from includes as well, they should not be able to leave the directory
+        let mut path = self.dir.clone();
+        for segment in name.split('/') {
+            match segment {
+                "" | "." | ".." => return Err(TemplateError::InvalidName(name.to_string())),
+                s if s.contains(['\\', '\0']) => return Err(TemplateError::InvalidName(name.to_string())),
+                s => path.push(s),
+            }
+        }
+        let source = fs::read_to_string(&path).map_err(|e| TemplateError::Io(name.to_string(), e))?;
+        let template = Arc::new(Template::parse(name, &source)?);
+        // two threads may both have parsed it, either result is as good
+        self.cache.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(name.to_string(), Arc::clone(&template));
+        Ok(template)
+    }
+
+    fn render_template(&self, name: &str, scope: &mut Scope, out: &mut String, depth: usize) -> Result<(), TemplateError> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(TemplateError::IncludeDepth(name.to_string()));
+        }
+        let template = self.get(name)?;
+        self.render_nodes(&template.nodes, scope, out, depth)
+    }
+
+    fn render_nodes(&self, nodes: &[Node], scope: &mut Scope, out: &mut String, depth: usize) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Output { path, raw } => {
+                    if let Some(value) = scope.lookup(path) {
+                        let text = value.to_string();
+                        if *raw { out.push_str(&text) } else { escape_into(&text, out) }
+                    }
+                },
+                Node::If { path, negate, then, otherwise } => {
+                    let truthy = scope.lookup(path).is_some_and(Value::is_truthy);
+                    let branch = if truthy != *negate { then } else { otherwise };
+                    self.render_nodes(branch, scope, out, depth)?;
+                },
+                Node::For { var, path, body, empty } => {
+                    let items = match scope.lookup(path) {
+                        Some(Value::List(items)) => items.as_slice(),
+                        _ => &[],
+                    };
+                    if items.is_empty() {
+                        self.render_nodes(empty, scope, out, depth)?;
+                    }
+                    for item in items {
+                        scope.locals.push((var.clone(), item));
+                        let rendered = self.render_nodes(body, scope, out, depth);
+                        scope.locals.pop();
+                        rendered?;
+                    }
+                },
+                Node::Include(name) => self.render_template(name, scope, out, depth + 1)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a template gets rendered with, built up like
+/// `Context::new().with("title", "Files").with("files", names)`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context(HashMap<String, Value>);
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Context {
+        self.insert(name, value);
+        self
+    }
+
+    pub fn insert(&mut self, name: &str, value: impl Into<Value>) {
+        self.0.insert(name.to_string(), value.into());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Bool(bool),
+    List(Vec<Value>),
+    /// Its fields are reached with dots, `{{ file.name }}`
+    Map(HashMap<String, Value>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(text) => !text.is_empty(),
+            Value::Bool(b) => *b,
+            Value::List(items) => !items.is_empty(),
+            Value::Map(fields) => !fields.is_empty(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Text(text) => f.write_str(text),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::List(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                Ok(())
+            },
+            // there is no sensible way to show one
+            Value::Map(_) => Ok(()),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Value {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Value {
+        Value::Text(text)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+macro_rules! number_values {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Value {
+            fn from(n: $t) -> Value {
+                Value::Text(n.to_string())
+            }
+        })*
+    };
+}
+
+number_values!(i32, i64, u16, u32, u64, usize, f64);
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Value {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    // a missing value is as good as an empty one
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Text(String::new()), Into::into)
+    }
+}
+
+impl From<Context> for Value {
+    fn from(context: Context) -> Value {
+        Value::Map(context.0)
+    }
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    /// The template couldnt be read, with its name
+    Io(String, io::Error),
+    Syntax { template: String, line: usize, message: String },
+    /// Empty, absolute or with `..` in it
+    InvalidName(String),
+    /// Includes nested deeper than [`MAX_INCLUDE_DEPTH`]
+    IncludeDepth(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Io(name, e) => write!(f, "failed to read template {}: {}", name, e),
+            TemplateError::Syntax { template, line, message } => write!(f, "{} line {}: {}", template, line, message),
+            TemplateError::InvalidName(name) => write!(f, "invalid template name {:?}", name),
+            TemplateError::IncludeDepth(name) => write!(f, "includes nested too deep at {}", name),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// A parsed template, see [`Templates`] for the syntax
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Output { path: Vec<String>, raw: bool },
+    If { path: Vec<String>, negate: bool, then: Vec<Node>, otherwise: Vec<Node> },
+    For { var: String, path: Vec<String>, body: Vec<Node>, empty: Vec<Node> },
+    Include(String),
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Output(&'a str),
+    Tag(&'a str),
+}
+
+impl Template {
+    pub fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let tokens = tokenize(name, source)?;
+        let mut parser = Parser { name, tokens: tokens.into_iter() };
+        let (nodes, end) = parser.block()?;
+        match end {
+            None => Ok(Template { nodes }),
+            Some((tag, line)) => Err(syntax(name, line, format!("unexpected {{% {} %}}", tag))),
+        }
+    }
+}
+
+// Splits the source into text, {{ }} and {% %}, with the line each one starts on
+fn tokenize<'a>(name: &str, source: &'a str) -> Result<Vec<(Token<'a>, usize)>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    let mut line = 1;
+    while !rest.is_empty() {
+        let Some(start) = rest.find("{{").into_iter().chain(rest.find("{%")).chain(rest.find("{#")).min() else {
+            tokens.push((Token::Text(rest), line));
+            break;
+        };
+        if start > 0 {
+            tokens.push((Token::Text(&rest[..start]), line));
+            line += rest[..start].matches('\n').count();
+        }
+        let close = match &rest[start..start + 2] {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let inner = &rest[start + 2..];
+        let Some(end) = inner.find(close) else {
+            return Err(syntax(name, line, format!("unclosed {}", &rest[start..start + 2])));
+        };
+        let content = &inner[..end];
+        match close {
+            "}}" => tokens.push((Token::Output(content.trim()), line)),
+            "%}" => tokens.push((Token::Tag(content.trim()), line)),
+            _ => {},
+        }
+        line += content.matches('\n').count();
+        rest = &inner[end + 2..];
+        // tags on lines of their own shouldnt leave empty lines behind
+        if close != "}}" && rest.starts_with('\n') {
+            rest = &rest[1..];
+            line += 1;
+        }
+    }
+    Ok(tokens)
+}
+
+// the else or end tag a block stopped at and its line, None at the end of the template
+type EndTag<'a> = Option<(&'a str, usize)>;
+
+struct Parser<'a, I> {
+    name: &'a str,
+    tokens: I,
+}
+
+impl<'a, I: Iterator<Item = (Token<'a>, usize)>> Parser<'a, I> {
+    // Parses nodes up to an else or end tag, returns which one it was along with its line.
+    // None means the end of the template was reached.
+    fn block(&mut self) -> Result<(Vec<Node>, EndTag<'a>), TemplateError> {
+        let mut nodes = Vec::new();
+        while let Some((token, line)) = self.tokens.next() {
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text.to_string())),
+                Token::Output(expr) => {
+                    let (expr, raw) = match expr.split_once('|') {
+                        Some((expr, filter)) if filter.trim() == "raw" => (expr.trim(), true),
+                        Some(_) => return Err(syntax(self.name, line, "the only filter is raw")),
+                        None => (expr, false),
+                    };
+                    nodes.push(Node::Output { path: self.path(expr, line)?, raw });
+                },
+                Token::Tag(tag) => {
+                    let mut words = tag.split_whitespace();
+                    match (words.next().unwrap_or(""), words.collect::<Vec<_>>().as_slice()) {
+                        ("if", ["not", expr]) => nodes.push(self.if_block(expr, true, line)?),
+                        ("if", [expr]) => nodes.push(self.if_block(expr, false, line)?),
+                        ("for", [var, "in", expr]) => {
+                            let path = self.path(expr, line)?;
+                            let (body, end) = self.block()?;
+                            let empty = self.rest_of_block(end, "for", "endfor", line)?;
+                            nodes.push(Node::For { var: var.to_string(), path, body, empty });
+                        },
+                        ("include", [quoted]) => {
+                            let name = quoted.strip_prefix('"').and_then(|q| q.strip_suffix('"'))
+                                .ok_or_else(|| syntax(self.name, line, "include expects a quoted name"))?;
+                            nodes.push(Node::Include(name.to_string()));
+                        },
+                        // whoever opened the block checks it is the end they were waiting for
+                        (end @ ("else" | "endif" | "endfor"), []) => return Ok((nodes, Some((end, line)))),
+                        _ => return Err(syntax(self.name, line, format!("unknown tag {{% {} %}}", tag))),
+                    }
+                },
+            }
+        }
+        Ok((nodes, None))
+    }
+
+    fn if_block(&mut self, expr: &str, negate: bool, line: usize) -> Result<Node, TemplateError> {
+        let path = self.path(expr, line)?;
+        let (then, end) = self.block()?;
+        let otherwise = self.rest_of_block(end, "if", "endif", line)?;
+        Ok(Node::If { path, negate, then, otherwise })
+    }
+
+    // The else branch if the first part ended with one, which then has to be followed by the
+    // end tag. `line` is where the block was opened.
+    fn rest_of_block(&mut self, end: EndTag, opened: &str, closing: &str, line: usize) -> Result<Vec<Node>, TemplateError> {
+        let (otherwise, end) = match end {
+            Some(("else", _)) => self.block()?,
+            end => (Vec::new(), end),
+        };
+        match end {
+            Some((tag, _)) if tag == closing => Ok(otherwise),
+            Some((tag, line)) => Err(syntax(self.name, line, format!("unexpected {{% {} %}}", tag))),
+            None => Err(syntax(self.name, line, format!("{{% {} %}} without {{% {} %}}", opened, closing))),
+        }
+    }
+
+    fn path(&self, expr: &str, line: usize) -> Result<Vec<String>, TemplateError> {
+        let path = expr.split('.').map(str::to_string).collect::<Vec<_>>();
+        let valid = |part: &String| !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if path.iter().all(valid) {
+            Ok(path)
+        } else {
+            Err(syntax(self.name, line, format!("invalid name {:?}", expr)))
+        }
+    }
+}
+
+fn syntax(name: &str, line: usize, message: impl Into<String>) -> TemplateError {
+    TemplateError::Syntax { template: name.to_string(), line, message: message.into() }
+}
+
+struct Scope<'a> {
+    context: &'a Context,
+    // loop variables, innermost last
+    locals: Vec<(String, &'a Value)>,
+}
+
+impl<'a> Scope<'a> {
+    fn lookup(&self, path: &[String]) -> Option<&'a Value> {
+        let (first, rest) = path.split_first()?;
+        let mut value = self.locals.iter().rev()
+            .find(|(name, _)| name == first)
+            .map(|(_, value)| *value)
+            .or_else(|| self.context.0.get(first))?;
+        for field in rest {
+            value = match value {
+                Value::Map(fields) => fields.get(field)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+}
+
+/// Escapes text for use in HTML content and quoted attribute values
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    escape_into(text, &mut out);
+    out
+}
+
+fn escape_into(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn render(source: &str, context: &Context) -> String {
+        let templates = Templates::new("does-not-exist");
+        templates.add("test.html", source).unwrap();
+        templates.render("test.html", context).unwrap()
+    }
+
+    #[test]
+    fn substitutes_and_escapes() {
+        let context = Context::new()
+            .with("path", "/<script>alert('hi')</script>")
+            .with("user", Context::new().with("name", "bob & co"))
+            .with("count", 3);
+        assert_eq!(
+            "/&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt; bob &amp; co 3 <b>",
+            render("{{ path }} {{user.name}} {{ count }} {{ missing }}{{ tag | raw }}", &context.with("tag", "<b>"))
+        );
+    }
+
+    #[test]
+    fn conditionals_and_loops() {
+        let files = vec![
+            Context::new().with("name", "a.txt").with("dir", false),
+            Context::new().with("name", "b").with("dir", true),
+        ];
+        let source = "{% for file in files %}[{{ file.name }}{% if file.dir %}/{% endif %}]{% else %}empty{% endfor %}\
+            {% if not files %}none{% else %}!{% endif %}";
+        assert_eq!("[a.txt][b/]!", render(source, &Context::new().with("files", files)));
+        assert_eq!("emptynone", render(source, &Context::new().with("files", Vec::<Value>::new())));
+        assert_eq!("emptynone", render(source, &Context::new()));
+        assert_eq!("a\nb\n", render("{% if x %}\na\n{% endif %}\n{# note #}\nb\n", &Context::new().with("x", true)));
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_lines() {
+        for (source, line) in [
+            ("{% if a %}\nunclosed", 1),
+            ("line\n{% endif %}", 2),
+            ("\n\n{{ a.b.", 3),
+            ("{% for a on b %}{% endfor %}", 1),
+            ("{% if a %}{% else %}{% endfor %}", 1),
+            ("{{ a | upper }}", 1),
+            ("{% if a %}\n{% endif %}\n{% endif %}", 3),
+        ] {
+            match Template::parse("test.html", source) {
+                Err(TemplateError::Syntax { line: l, .. }) => assert_eq!(line, l, "{}", source),
+                other => panic!("{:?} for {}", other, source),
+            }
+        }
+    }
+
+    #[test]
+    fn loads_includes_from_the_directory() {
+        let dir = TestDir::new("templates");
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        fs::write(dir.join("page.html"), "{% include \"parts/header.html\" %}body").unwrap();
+        fs::write(dir.join("parts/header.html"), "<h1>{{ title }}</h1>").unwrap();
+        fs::write(dir.join("loop.html"), "{% include \"loop.html\" %}").unwrap();
+        fs::write(dir.join("escape.html"), "{% include \"../page.html\" %}").unwrap();
+        let templates = Templates::new(&*dir);
+        assert_eq!("<h1>Hi</h1>body", templates.render("page.html", &Context::new().with("title", "Hi")).unwrap());
+
+        // served from the cache from now on
+        fs::write(dir.join("parts/header.html"), "changed").unwrap();
+        assert_eq!("<h1></h1>body", templates.render("page.html", &Context::new()).unwrap());
+
+        assert!(matches!(templates.render("loop.html", &Context::new()), Err(TemplateError::IncludeDepth(_))));
+        assert!(matches!(templates.render("escape.html", &Context::new()), Err(TemplateError::InvalidName(_))));
+        assert!(matches!(templates.render("missing.html", &Context::new()), Err(TemplateError::Io(..))));
+        assert_eq!(500, templates.page(200, "missing.html", &Context::new()).status);
+    }
+}
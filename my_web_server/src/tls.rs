@@ -0,0 +1,144 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+
+use crate::http::{Request, Response};
+use crate::router::Router;
+
+/// Loads a PEM certificate chain and private key for serving HTTPS
+pub fn server_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("bad certificate {}: {}", cert_path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", cert_path.display())));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| invalid(format!("bad private key {}: {}", key_path.display(), e)))?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(e.to_string()))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Answers every request with a permanent redirect to the same path on `host` and
+/// `https_port`, for serving on the plaintext port next to an HTTPS listener
+pub fn redirect_to_https(host: String, https_port: u16) -> Router {
+    let mut router = Router::new();
+    router.not_found(move |request| {
+        let location = https_location(request, &host, https_port);
+        Response::new(308).with_header("Location", &location)
+    });
+    router
+}
+
+// Always to the configured host, the client's Host header would make this an open redirect
+fn https_location(request: &Request, host: &str, https_port: u16) -> String {
+    let mut location = if host.contains(':') && !host.starts_with('[') {
+        format!("https://[{}]", host)
+    } else {
+        format!("https://{}", host)
+    };
+    if https_port != 443 {
+        location.push_str(&format!(":{}", https_port));
+    }
+    location.push_str(&request.path);
+    if let Some(query) = &request.query {
+        location.push('?');
+        location.push_str(query);
+    }
+    location
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{handle_connection, ConnectionOptions};
+    use crate::test_dir::TestDir;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // a fresh self signed certificate for localhost, written where server_config can load it
+    fn self_signed(name: &str) -> (Arc<ServerConfig>, CertificateDer<'static>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = TestDir::new(name);
+        fs::write(dir.join("cert.pem"), certified.cert.pem()).unwrap();
+        fs::write(dir.join("key.pem"), certified.key_pair.serialize_pem()).unwrap();
+        let config = server_config(&dir.join("cert.pem"), &dir.join("key.pem")).unwrap();
+        (config, certified.cert.der().clone())
+    }
+
+    #[test]
+    fn serves_requests_over_tls() {
+        let (server_config, cert) = self_signed("tls");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut router = Router::new();
+            router.get("/", |_| Response::text(200, "secret"));
+            let options = ConnectionOptions { tls: Some(server_config), ..Default::default() };
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &router, &options);
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let connection = rustls::ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap();
+        let mut client = rustls::StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("secret"));
+    }
+
+    #[test]
+    fn plaintext_request_to_tls_port_fails() {
+        let (server_config, _) = self_signed("plaintext");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let options = ConnectionOptions { tls: Some(server_config), ..Default::default() };
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &Router::new(), &options);
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        let _ = client.read_to_end(&mut response);
+        assert!(!response.starts_with(b"HTTP/1.1"));
+    }
+
+    #[test]
+    fn redirects_to_the_https_port() {
+        let router = redirect_to_https("localhost".to_string(), 8443);
+        let mut request = Request { method: "POST".to_string(), path: "/a/b".to_string(), query: Some("c=d".to_string()), ..Default::default() };
+        request.headers.insert("host".to_string(), "evil.example:8080".to_string());
+        let response = router.handle(&mut request);
+        assert_eq!(308, response.status);
+        assert_eq!(Some("https://localhost:8443/a/b?c=d"), response.header("location"));
+
+        let response = redirect_to_https("::1".to_string(), 443).handle(&mut request);
+        assert_eq!(Some("https://[::1]/a/b?c=d"), response.header("location"));
+    }
+
+    #[test]
+    fn rejects_missing_key() {
+        let dir = TestDir::new("nokey");
+        fs::write(dir.join("cert.pem"), "").unwrap();
+        assert!(server_config(&dir.join("cert.pem"), &dir.join("key.pem")).is_err());
+    }
+}
@@ -0,0 +1,706 @@
+use std::io::{self, BufRead, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+use crate::http::{self, Limits, ParseError, Request, Response, Upgrade, MAX_CHUNK_LINE};
+use crate::panic_message;
+use crate::router::Router;
+use crate::server::{self, error_response, record, respond, ConnectionOptions};
+
+/// Serves like [`server::serve`](crate::server::serve) does, with the same router and
+/// options, but every connection is a tokio task instead of a thread from the pool.
+///
+/// Waiting on a connection, for the next request or for a slow client, costs no thread, so
+/// thousands of idle keep-alive connections are fine. Handlers are still blocking code and
+/// run on tokio's blocking threads, up to 512 at a time. `config.workers` is the number of
+/// threads driving the connections themselves.
+pub fn serve(listener: net::TcpListener, config: &Config, router: Arc<Router>) -> io::Result<bool> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.workers)
+        .enable_all()
+        .build()?;
+    let finished = runtime.block_on(accept(listener, config, router));
+    // handlers still running after the shutdown timeout are left behind, like with the pool
+    runtime.shutdown_background();
+    finished
+}
+
+async fn accept(listener: net::TcpListener, config: &Config, router: Arc<Router>) -> io::Result<bool> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let options = Arc::new(config.connection.clone());
+    // tells idle connections to close when shutting down, busy ones finish their request first
+    let (closing, closed) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let mut accepted = 0;
+    // the shutdown flag is set from a signal handler, nothing wakes us up for it
+    let mut poll = time::interval(Duration::from_millis(50));
+    while !options.shutdown.load(Ordering::SeqCst) && config.max_connections.is_none_or(|max| accepted < max) {
+        tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, client)) => {
+                    accepted += 1;
+                    let connection = handle_connection(stream, client, Arc::clone(&router), Arc::clone(&options), closed.clone());
+                    connections.spawn(connection);
+                },
+                // same as with threads, not a reason to stop serving everybody else
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    time::sleep(Duration::from_millis(50)).await;
+                },
+            },
+            _ = poll.tick() => {},
+        }
+        while connections.try_join_next().is_some() {}
+    }
+    info!("Shutting down");
+    // after max_connections the ones we have get to finish, like with threads
+    if options.shutdown.load(Ordering::SeqCst) {
+        let _ = closing.send(true);
+    }
+    let drained = async { while connections.join_next().await.is_some() {} };
+    Ok(time::timeout(config.shutdown_timeout, drained).await.is_ok())
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    client: SocketAddr,
+    router: Arc<Router>,
+    options: Arc<ConnectionOptions>,
+    mut closing: watch::Receiver<bool>,
+) {
+    let client = Some(client);
+    debug!("Connection established with {:?}", client);
+    let _open = options.metrics.as_ref().map(|metrics| metrics.connection_opened());
+    let mut input = Input { buffer: Vec::new(), eof: false };
+
+    for served in 1.. {
+        // waiting for the next request only counts against the idle timeout, and is cut short
+        // when shutting down
+        if input.buffer.is_empty() {
+            tokio::select! {
+                read = time::timeout(options.idle_timeout, input.read_from(&mut stream)) => match read {
+                    Ok(Ok(0)) | Err(_) => break,
+                    Ok(Ok(_)) => {},
+                    Ok(Err(e)) => {
+                        debug!("Failed to read request from {:?}: {}", client, e);
+                        break;
+                    },
+                },
+                _ = closing.changed() => break,
+            }
+        }
+        let deadline = Instant::now() + options.request_timeout;
+        let (request, streamed_body) = match read_request(&mut stream, &mut input, &router, &options, deadline).await {
+            Ok(read) => read,
+            Err(ParseError::ConnectionClosed) => break,
+            Err(e) => {
+                debug!("Failed to read request from {:?}: {}", client, e);
+                if let Some(mut response) = error_response(&e) {
+                    let mut bytes = Vec::new();
+                    let _ = response.write_to(&mut bytes);
+                    let _ = write(&mut stream, &bytes, options.write_timeout).await;
+                }
+                break;
+            },
+        };
+        let started = std::time::Instant::now();
+
+        // handlers block, on a thread of their own they cant hold up other connections
+        let handled = match streamed_body {
+            None => {
+                let (router, options) = (Arc::clone(&router), Arc::clone(&options));
+                task::spawn_blocking(move || {
+                    let mut request = request;
+                    let (response, keep_alive) = respond(&router, &mut request, client, &options, served);
+                    (request, response, keep_alive)
+                })
+                .await
+            },
+            Some(framing) => {
+                let (router, handler_options) = (Arc::clone(&router), Arc::clone(&options));
+                feed_body(&mut stream, &mut input, options.idle_timeout, move |pulled| {
+                    let mut request = request;
+                    let (response, keep_alive, pulled) =
+                        server::respond_streaming(&router, &mut request, client, &handler_options, served, pulled, framing);
+                    ((request, response, keep_alive), pulled)
+                })
+                .await
+            },
+        };
+        // respond catches panics, so this only fails when the runtime is going away
+        let Ok((request, mut response, keep_alive)) = handled else { break };
+        let upgrade = response.upgrade.take();
+        let written = send(&mut stream, &mut response, options.write_timeout).await;
+        record(&options, client, &request, &response, *written.as_ref().unwrap_or(&0), started);
+        // most likely the client went away, so there is nobody left to tell
+        if let Err(e) = written {
+            debug!("Failed to write response to {:?}: {}", client, e);
+            break;
+        }
+        if let Some(upgrade) = upgrade {
+            hand_over(stream, input.buffer, upgrade, &request, &options).await;
+            return;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+    let _ = stream.shutdown().await;
+}
+
+// What has come in on the connection but hasnt been parsed yet
+struct Input {
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl Input {
+    async fn read_from(&mut self, stream: &mut TcpStream) -> io::Result<usize> {
+        self.buffer.reserve(16 * 1024);
+        let read = stream.read_buf(&mut self.buffer).await?;
+        self.eof = read == 0;
+        Ok(read)
+    }
+
+    // reads more of the request, with the same timeouts as the threaded server
+    async fn read_more(&mut self, stream: &mut TcpStream, idle_timeout: Duration, deadline: Instant) -> Result<(), ParseError> {
+        let until = deadline.min(Instant::now() + idle_timeout);
+        match time::timeout_at(until, self.read_from(stream)).await {
+            Ok(read) => read.map(|_| ()).map_err(ParseError::Io),
+            Err(_) => Err(ParseError::Io(io::Error::new(io::ErrorKind::TimedOut, "request took too long to arrive"))),
+        }
+    }
+}
+
+// The request is parsed by the same code as with threads, it just gets retried once more
+// has come in. To keep that from going quadratic, the head is only parsed again after
+// another line break and the body only once it has arrived in full.
+// For routes which stream their body only the head is read, the framing of the body comes
+// back with it
+async fn read_request(
+    stream: &mut TcpStream,
+    input: &mut Input,
+    router: &Router,
+    options: &ConnectionOptions,
+    deadline: Instant,
+) -> Result<(Request, Option<http::Framing>), ParseError> {
+    let limits = &options.limits;
+    // past this the parser will find a line which is too long, whether or not it ended yet
+    let head_limit = limits.max_request_line + limits.max_header_bytes + 4;
+    let (mut request, head_length) = loop {
+        // empty lines before a request are allowed, dropping them keeps the buffer from growing
+        let blank = input.buffer.iter().take_while(|b| matches!(b, b'\r' | b'\n')).count();
+        input.buffer.drain(..blank);
+        let mut reader = Parsed { data: &input.buffer, position: 0, eof: input.eof };
+        match Request::read_head(&mut reader, limits) {
+            Ok(request) => break (request, reader.position),
+            Err(e) if needs_more(&e) => {},
+            Err(e) => return Err(e),
+        }
+        loop {
+            let before = input.buffer.len();
+            input.read_more(stream, options.idle_timeout, deadline).await?;
+            if input.eof || input.buffer[before..].contains(&b'\n') || input.buffer.len() > head_limit {
+                break;
+            }
+        }
+    };
+
+    if router.streams_body(&request) {
+        let framing = http::framing(&request)?;
+        input.buffer.drain(..head_length);
+        return Ok((request, Some(framing)));
+    }
+
+    let mut framing = Framing::of(&request, limits);
+    loop {
+        if input.eof || framing.complete(&input.buffer[head_length..], limits) {
+            let mut reader = Parsed { data: &input.buffer[head_length..], position: 0, eof: input.eof };
+            match http::read_body(&mut reader, &request, limits) {
+                Ok(body) => {
+                    let used = head_length + reader.position;
+                    request.body = body;
+                    input.buffer.drain(..used);
+                    return Ok((request, None));
+                },
+                Err(e) if needs_more(&e) => {},
+                Err(e) => return Err(e),
+            }
+        }
+        input.read_more(stream, options.idle_timeout, deadline).await?;
+    }
+}
+
+// Runs the handler of a streaming route on a blocking thread while passing it the body, one
+// read from the connection whenever it asks, so nothing past the body is read on its behalf
+async fn feed_body<T, F>(stream: &mut TcpStream, input: &mut Input, idle_timeout: Duration, handle: F) -> Result<T, task::JoinError>
+    where
+        F: FnOnce(Pulled) -> (T, Pulled),
+        F: Send + 'static,
+        T: Send + 'static,
+{
+    let (wanted, mut wants) = mpsc::channel(1);
+    let (pieces, received) = mpsc::channel(1);
+    let pulled = Pulled { buffer: std::mem::take(&mut input.buffer), position: 0, eof: input.eof, wanted, received };
+    let mut handler = task::spawn_blocking(move || handle(pulled));
+    let handled = loop {
+        tokio::select! {
+            handled = &mut handler => break handled,
+            Some(()) = wants.recv() => {
+                let mut piece = Vec::with_capacity(16 * 1024);
+                let read = match time::timeout(idle_timeout, stream.read_buf(&mut piece)).await {
+                    Ok(read) => read.map(|_| piece),
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "client stopped sending the body")),
+                };
+                let _ = pieces.send(read).await;
+            },
+        }
+    };
+    let (handled, pulled) = handled?;
+    // anything after the body is the start of the next request
+    input.buffer = pulled.buffer[pulled.position..].to_vec();
+    input.eof = pulled.eof;
+    Ok(handled)
+}
+
+// A streamed body for its blocking handler, what came in along with the head first and then
+// whatever the connection's task reads for it
+struct Pulled {
+    buffer: Vec<u8>,
+    position: usize,
+    eof: bool,
+    wanted: mpsc::Sender<()>,
+    received: mpsc::Receiver<io::Result<Vec<u8>>>,
+}
+
+impl Read for Pulled {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for Pulled {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.buffer.len() && !self.eof {
+            let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "connection closed");
+            self.wanted.blocking_send(()).map_err(|_| closed())?;
+            let piece = self.received.blocking_recv().ok_or_else(closed)??;
+            self.eof = piece.is_empty();
+            self.buffer = piece;
+            self.position = 0;
+        }
+        Ok(&self.buffer[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount;
+    }
+}
+
+fn needs_more(e: &ParseError) -> bool {
+    matches!(e, ParseError::Io(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+// The unparsed input as a reader which runs out with WouldBlock instead of an end of file
+// while the connection is still open, so an incomplete request can be told from a bad one
+struct Parsed<'a> {
+    data: &'a [u8],
+    position: usize,
+    eof: bool,
+}
+
+impl Read for Parsed<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for Parsed<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let rest = &self.data[self.position..];
+        if rest.is_empty() && !self.eof {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "request incomplete"));
+        }
+        Ok(rest)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount;
+    }
+}
+
+// Tells when the whole body is in without decoding it. Anything odd counts as complete, the
+// parser then says what is wrong with it.
+#[derive(Debug)]
+enum Framing {
+    Length(u64),
+    Chunked {
+        // where the next chunk size or trailer line starts
+        next_line: usize,
+        received: u64,
+        trailers: bool,
+    },
+}
+
+impl Framing {
+    fn of(request: &Request, limits: &Limits) -> Framing {
+        let content_length = request.header("content-length");
+        match (request.header("transfer-encoding"), content_length) {
+            (Some(_), None) => Framing::Chunked { next_line: 0, received: 0, trailers: false },
+            (None, Some(length)) => match length.parse() {
+                Ok(length) if length <= limits.max_body => Framing::Length(length),
+                _ => Framing::Length(0),
+            },
+            _ => Framing::Length(0),
+        }
+    }
+
+    fn complete(&mut self, body: &[u8], limits: &Limits) -> bool {
+        match self {
+            Framing::Length(length) => body.len() as u64 >= *length,
+            Framing::Chunked { next_line, received, trailers } => loop {
+                let rest = body.get(*next_line..).unwrap_or(&[]);
+                let Some(end) = rest.iter().position(|b| *b == b'\n') else {
+                    return rest.len() > MAX_CHUNK_LINE.max(limits.max_header_bytes) + 2;
+                };
+                let line = &rest[..end];
+                *next_line += end + 1;
+                if *trailers {
+                    *received += line.len() as u64;
+                    if line.is_empty() || line == b"\r" || *received > limits.max_body + limits.max_header_bytes as u64 {
+                        return true;
+                    }
+                    continue;
+                }
+                let size = std::str::from_utf8(line)
+                    .ok()
+                    .and_then(|line| u64::from_str_radix(line.split(';').next().unwrap_or("").trim(), 16).ok());
+                match size {
+                    Some(0) => *trailers = true,
+                    // the size is the client's, adding to it could overflow
+                    Some(size) if limits.max_body.checked_sub(*received).is_some_and(|left| size <= left) => {
+                        *received += size;
+                        // past the data and the line break after it
+                        *next_line += size as usize + 2;
+                    },
+                    _ => return true,
+                }
+            },
+        }
+    }
+}
+
+async fn send(stream: &mut TcpStream, response: &mut Response, write_timeout: Duration) -> io::Result<u64> {
+    if !matches!(response.body, http::Body::Stream { .. }) {
+        let mut bytes = Vec::new();
+        let sent = response.write_to(&mut bytes)?;
+        write(stream, &bytes, write_timeout).await?;
+        return Ok(sent);
+    }
+    // a streamed body is read by blocking code, which passes it over a few pieces at a time.
+    // The status and headers stay behind for the access log.
+    let body = std::mem::replace(&mut response.body, http::Body::Full(Vec::new()));
+    let mut streamed = Response { status: response.status, headers: response.headers.clone(), body, upgrade: None };
+    let (sender, mut receiver) = mpsc::channel(4);
+    let producer = task::spawn_blocking(move || {
+        let mut writer = io::BufWriter::with_capacity(16 * 1024, Channel(sender));
+        streamed.write_to(&mut writer).and_then(|sent| writer.flush().map(|_| sent))
+    });
+    let mut failed = None;
+    while let Some(piece) = receiver.recv().await {
+        if let Err(e) = write(stream, &piece, write_timeout).await {
+            failed = Some(e);
+            break;
+        }
+    }
+    // so the producer's next send fails instead of waiting for us forever
+    drop(receiver);
+    let produced = match producer.await {
+        Ok(produced) => produced,
+        Err(e) if e.is_panic() => {
+            let message = panic_message(e.into_panic().as_ref()).to_string();
+            error!("Response body panicked: {}", message);
+            Err(io::Error::other("response body panicked"))
+        },
+        Err(_) => Err(io::Error::other("shutting down")),
+    };
+    match failed {
+        Some(e) => Err(e),
+        None => produced,
+    }
+}
+
+async fn write(stream: &mut TcpStream, bytes: &[u8], timeout: Duration) -> io::Result<()> {
+    match time::timeout(timeout, stream.write_all(bytes)).await {
+        Ok(written) => written,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "client stopped reading the response")),
+    }
+}
+
+// Hands what the blocking response body writes over to the connection's task
+struct Channel(mpsc::Sender<Vec<u8>>);
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.blocking_send(buf.to_vec()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Upgrade handlers are blocking code like all the others, so the connection goes back to
+// being a blocking socket for them, on a blocking thread
+async fn hand_over(stream: TcpStream, buffered: Vec<u8>, upgrade: Upgrade, request: &Request, options: &ConnectionOptions) {
+    let stream = stream.into_std().and_then(|stream| {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(options.websocket_idle_timeout))?;
+        stream.set_write_timeout(Some(options.write_timeout))?;
+        Ok(stream)
+    });
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Failed to hand over connection: {}", e);
+            return;
+        },
+    };
+    let upgraded = task::spawn_blocking(move || {
+        // whatever the client sent right after the handshake is already in the buffer
+        let mut upgraded = Upgraded(Read::chain(io::Cursor::new(buffered), stream));
+        upgrade.run(&mut upgraded);
+    });
+    if let Err(e) = upgraded.await {
+        if e.is_panic() {
+            error!("Upgrade for {} {} panicked: {}", request.method, request.path, panic_message(e.into_panic().as_ref()));
+        }
+    }
+}
+
+struct Upgraded(io::Chain<io::Cursor<Vec<u8>>, net::TcpStream>);
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().1.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().1.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::Message;
+    use std::io::{BufRead, BufReader};
+    use std::thread;
+
+    // serves max_connections connections with tokio in the background
+    fn start(router: Router, max_connections: usize) -> (SocketAddr, thread::JoinHandle<bool>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = Config { workers: 1, max_connections: Some(max_connections), ..Default::default() };
+        let server = thread::spawn(move || serve(listener, &config, Arc::new(router)).unwrap());
+        (address, server)
+    }
+
+    fn read_response(reader: &mut impl BufRead) -> (String, Vec<u8>) {
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert!(reader.read_line(&mut head).unwrap() > 0, "closed after {:?}", head);
+        }
+        let length = head.lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (head, body)
+    }
+
+    #[test]
+    fn answers_pipelined_and_split_requests() {
+        let mut router = Router::new();
+        router.post("/echo", |request| Response::text(200, request.body.clone()));
+        router.get("/hello", |_| Response::text(200, "hello"));
+        let (address, server) = start(router, 1);
+
+        let mut client = net::TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        client.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcdeGET /hello HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(b"abcde", &read_response(&mut reader).1[..]);
+        assert_eq!(b"hello", &read_response(&mut reader).1[..]);
+
+        // a chunked body trickling in, split in awkward places
+        for piece in ["POST /echo HTTP/1.1\r\nTransfer-Enc", "oding: chunked\r\n\r\n3\r\nab", "c\r\n", "2\r\n\r\n\r\n0\r\n", "\r\n"] {
+            client.write_all(piece.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(b"abc\r\n", &read_response(&mut reader).1[..]);
+
+        client.write_all(b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Connection: close"));
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn streaming_routes_read_the_body_as_it_comes() {
+        let mut router = Router::new();
+        router.streaming("PUT", "/count", |request| {
+            let mut body = request.body_reader();
+            let mut first = [0; 5];
+            body.read_exact(&mut first).unwrap();
+            let rest = io::copy(&mut body, &mut io::sink()).unwrap();
+            Response::text(200, format!("{} {}", String::from_utf8_lossy(&first), rest))
+        });
+        router.get("/hello", |_| Response::text(200, "hello"));
+        let (address, server) = start(router, 1);
+
+        let mut client = net::TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        // bigger than the body size limit, in pieces with the next request right behind it
+        client.write_all(b"PUT /count HTTP/1.1\r\nContent-Length: 20000005\r\n\r\nhello").unwrap();
+        for _ in 0..20 {
+            client.write_all(&[b'x'; 1_000_000]).unwrap();
+        }
+        client.write_all(b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert_eq!(b"hello 20000000", &read_response(&mut reader).1[..]);
+        assert_eq!(b"hello", &read_response(&mut reader).1[..]);
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn bad_requests_get_the_same_answers() {
+        let (address, server) = start(Router::new(), 2);
+        let mut client = net::TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /x HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n").unwrap();
+        let (head, _) = read_response(&mut BufReader::new(client));
+        assert!(head.starts_with("HTTP/1.1 413"), "{}", head);
+
+        let mut client = net::TcpStream::connect(address).unwrap();
+        client.write_all(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(10_000)).as_bytes()).unwrap();
+        let (head, _) = read_response(&mut BufReader::new(client));
+        assert!(head.starts_with("HTTP/1.1 414"), "{}", head);
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn slow_handlers_dont_hold_up_others() {
+        let mut router = Router::new();
+        router.get("/sleep", |_| {
+            thread::sleep(Duration::from_millis(300));
+            Response::text(200, "slept")
+        });
+        let (address, server) = start(router, 10);
+        let started = std::time::Instant::now();
+        // ten slow requests with a single worker thread
+        let clients = (0..10)
+            .map(|_| thread::spawn(move || {
+                let mut client = net::TcpStream::connect(address).unwrap();
+                client.write_all(b"GET /sleep HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+                read_response(&mut BufReader::new(client)).1
+            }))
+            .collect::<Vec<_>>();
+        for client in clients {
+            assert_eq!(b"slept", &client.join().unwrap()[..]);
+        }
+        assert!(started.elapsed() < Duration::from_millis(1500), "{:?}", started.elapsed());
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn streams_bodies_and_upgrades() {
+        let mut router = Router::new();
+        router.get("/stream", |_| Response::new(200).with_chunks((0..3).map(|i| format!("part {}\n", i).into_bytes())));
+        router.websocket("/ws", |_, socket| {
+            while let Ok(Some(Message::Text(text))) = socket.recv() {
+                socket.send_text(&text.to_uppercase()).unwrap();
+            }
+        });
+        let (address, server) = start(router, 1);
+        let mut client = net::TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        client.write_all(b"GET /stream HTTP/1.1\r\n\r\n").unwrap();
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        let mut body = Vec::new();
+        while !body.ends_with(b"0\r\n\r\n") {
+            reader.read_until(b'\n', &mut body).unwrap();
+        }
+        assert_eq!(b"7\r\npart 0\n\r\n7\r\npart 1\n\r\n7\r\npart 2\n\r\n0\r\n\r\n", &body[..]);
+
+        // the first frame goes out along with the handshake, so it is buffered when the upgrade happens
+        let mut handshake = b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n".to_vec();
+        handshake.extend_from_slice(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i']);
+        client.write_all(&handshake).unwrap();
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101"));
+        let mut frame = [0; 4];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!([0x81, 2, b'H', b'I'], frame);
+        client.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+        drop(client);
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn framing_waits_for_the_whole_chunked_body() {
+        let limits = Limits::default();
+        let mut request = Request::default();
+        request.headers.insert("transfer-encoding".to_string(), "chunked".to_string());
+        let body = b"3\r\nabc\r\n10\r\n0123456789abcdef\r\n0\r\nTrailer: x\r\n\r\nGET";
+        let mut framing = Framing::of(&request, &limits);
+        for end in [0, 5, 8, 20, 30, 37, 45] {
+            assert!(!framing.complete(&body[..end], &limits), "{}", end);
+        }
+        assert!(framing.complete(&body[..], &limits));
+        assert!(Framing::of(&request, &limits).complete(b"zz\r\n", &limits));
+    }
+
+    #[test]
+    fn framing_gives_up_on_chunks_past_the_limit() {
+        let limits = Limits::default();
+        let mut request = Request::default();
+        request.headers.insert("transfer-encoding".to_string(), "chunked".to_string());
+        // big enough to wrap around when added to the first chunk
+        let body = b"1\r\na\r\nffffffffffffffff\r\n";
+        assert!(Framing::of(&request, &limits).complete(body, &limits));
+        let mut reader = Parsed { data: body, position: 0, eof: false };
+        assert_eq!(Some(413), http::read_body(&mut reader, &request, &limits).unwrap_err().status());
+    }
+}
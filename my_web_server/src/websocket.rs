@@ -0,0 +1,433 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+use crate::http::{Request, Response};
+
+/// Anything a websocket can run over, a plain or a TLS connection
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// The handler for a websocket route, it gets the whole connection until it returns
+pub type WebSocketHandler = Arc<dyn Fn(&Request, &mut WebSocket) + Send + Sync>;
+
+// from RFC 6455, every server appends it to the client's key
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Messages bigger than this, all fragments together, close the connection with 1009
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Already answered with a pong by the time it is handed out
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The status code and reason, if the other side gave one
+    Close(Option<(u16, String)>),
+}
+
+/// Close codes from RFC 6455
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const TOO_BIG: u16 = 1009;
+}
+
+/// The value for `Sec-WebSocket-Accept`
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.finalize())
+}
+
+/// Checks the opening handshake and answers it with a 101 which hands the connection over to
+/// the handler once it is written. Used by [`Router::websocket`](crate::router::Router::websocket).
+pub fn upgrade(request: &Request, handler: WebSocketHandler) -> Response {
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return Response::text(426, "Upgrade Required")
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade");
+    }
+    if request.header("sec-websocket-version") != Some("13") {
+        return Response::text(426, "Unsupported WebSocket Version").with_header("Sec-WebSocket-Version", "13");
+    }
+    // the key is 16 random bytes, base64 encoded
+    let key = request.header("sec-websocket-key").unwrap_or("").trim();
+    let decoded = base64::engine::general_purpose::STANDARD.decode(key);
+    if decoded.map_or(true, |key| key.len() != 16) {
+        return Response::text(400, "Invalid Sec-WebSocket-Key");
+    }
+
+    let request = request.clone();
+    Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key))
+        .with_upgrade(move |stream| {
+            let mut socket = WebSocket::new(stream);
+            handler(&request, &mut socket);
+            // the handler may have just returned without saying goodbye
+            if !socket.close_sent {
+                let _ = socket.close(close_code::NORMAL, "");
+            }
+        })
+}
+
+/// A websocket connection from the server's side, messages are read with [`recv`](WebSocket::recv)
+/// and sent with [`send`](WebSocket::send).
+///
+/// Pings are answered and a close from the client is echoed automatically, fragmented
+/// messages are put back together. Protocol errors close the connection with the right code
+/// and come back from `recv` as `InvalidData` errors.
+pub struct WebSocket<'a> {
+    stream: &'a mut dyn Stream,
+    close_sent: bool,
+    close_received: bool,
+    // an unfinished text or binary message with its opcode, kept while control frames in
+    // between are handed out
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl<'a> WebSocket<'a> {
+    pub fn new(stream: &'a mut dyn Stream) -> WebSocket<'a> {
+        WebSocket { stream, close_sent: false, close_received: false, fragments: None }
+    }
+
+    /// The next message, None once the connection has been closed
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            if self.close_received {
+                return Ok(None);
+            }
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                // continuation
+                0x0 => {
+                    let Some((opcode, mut payload)) = self.fragments.take() else {
+                        return self.fail(close_code::PROTOCOL_ERROR, "continuation without a message to continue");
+                    };
+                    if payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                        return self.fail(close_code::TOO_BIG, "message too big");
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return self.data_message(opcode, payload).map(Some);
+                    }
+                    self.fragments = Some((opcode, payload));
+                },
+                0x1 | 0x2 => {
+                    if self.fragments.is_some() {
+                        return self.fail(close_code::PROTOCOL_ERROR, "new message in the middle of a fragmented one");
+                    }
+                    if frame.fin {
+                        return self.data_message(frame.opcode, frame.payload).map(Some);
+                    }
+                    self.fragments = Some((frame.opcode, frame.payload));
+                },
+                0x8 => {
+                    self.close_received = true;
+                    let close = match frame.payload.len() {
+                        0 => None,
+                        1 => return self.fail(close_code::PROTOCOL_ERROR, "close payload of one byte"),
+                        _ => {
+                            let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                            let Ok(reason) = String::from_utf8(frame.payload[2..].to_vec()) else {
+                                return self.fail(close_code::INVALID_DATA, "close reason is not utf-8");
+                            };
+                            Some((code, reason))
+                        },
+                    };
+                    if !self.close_sent {
+                        let code = close.as_ref().map_or(close_code::NORMAL, |(code, _)| *code);
+                        self.close(code, "")?;
+                    }
+                    return Ok(Some(Message::Close(close)));
+                },
+                0x9 => {
+                    if !self.close_sent {
+                        self.write_frame(0xA, &frame.payload)?;
+                    }
+                    return Ok(Some(Message::Ping(frame.payload)));
+                },
+                0xA => return Ok(Some(Message::Pong(frame.payload))),
+                _ => return self.fail(close_code::PROTOCOL_ERROR, "unknown opcode"),
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(0x1, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(0x2, &data),
+            Message::Ping(data) => self.write_frame(0x9, &data),
+            Message::Pong(data) => self.write_frame(0xA, &data),
+            Message::Close(None) => self.close(close_code::NORMAL, ""),
+            Message::Close(Some((code, reason))) => self.close(code, &reason),
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(0x1, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(0x2, data)
+    }
+
+    /// Starts the closing handshake, `recv` then returns the client's close once it answers
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        // control frames can only carry 125 bytes
+        payload.extend(reason.as_bytes().iter().take(123));
+        self.close_sent = true;
+        self.write_frame(0x8, &payload)
+    }
+
+    fn data_message(&mut self, opcode: u8, payload: Vec<u8>) -> io::Result<Message> {
+        if opcode == 0x2 {
+            return Ok(Message::Binary(payload));
+        }
+        match String::from_utf8(payload) {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => self.fail(close_code::INVALID_DATA, "text message is not utf-8"),
+        }
+    }
+
+    // closes with the code and returns the reason as an error
+    fn fail<T>(&mut self, code: u16, reason: &'static str) -> io::Result<T> {
+        let _ = self.close(code, reason);
+        self.close_received = true;
+        Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+    }
+
+    fn read_frame(&mut self) -> io::Result<Frame> {
+        let mut head = [0; 2];
+        self.stream.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        // no extensions are negotiated, so none of the reserved bits may be set
+        if head[0] & 0x70 != 0 {
+            return self.fail(close_code::PROTOCOL_ERROR, "reserved bits set");
+        }
+        if head[1] & 0x80 == 0 {
+            return self.fail(close_code::PROTOCOL_ERROR, "client frames must be masked");
+        }
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                self.stream.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            },
+            127 => {
+                let mut length = [0; 8];
+                self.stream.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            },
+            length => length as u64,
+        };
+        if opcode >= 0x8 && (!fin || length > 125) {
+            return self.fail(close_code::PROTOCOL_ERROR, "control frames must be short and unfragmented");
+        }
+        if length > MAX_MESSAGE_SIZE as u64 {
+            return self.fail(close_code::TOO_BIG, "message too big");
+        }
+        let mut mask = [0; 4];
+        self.stream.read_exact(&mut mask)?;
+        let mut payload = vec![0; length as usize];
+        self.stream.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    // server frames are never masked or fragmented
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use crate::server::{handle_connection, ConnectionOptions};
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // what a client would send, masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // reads from what the client sent, collects what the server writes
+    struct Pipe {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn pipe(frames: &[Vec<u8>]) -> Pipe {
+        Pipe { input: io::Cursor::new(frames.concat()), output: Vec::new() }
+    }
+
+    #[test]
+    fn accept_key_from_the_rfc() {
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+    }
+
+    #[test]
+    fn reassembles_fragments_and_answers_pings() {
+        let mut pipe = pipe(&[
+            client_frame(false, 0x1, b"hel"),
+            client_frame(true, 0x9, b"are you there"),
+            client_frame(true, 0x0, b"lo"),
+            client_frame(true, 0x2, &[0; 300]),
+            client_frame(true, 0x8, &[0x03, 0xE8, b'b', b'y', b'e']),
+        ]);
+        let mut socket = WebSocket::new(&mut pipe);
+        assert_eq!(Some(Message::Ping(b"are you there".to_vec())), socket.recv().unwrap());
+        assert_eq!(Some(Message::Text("hello".to_string())), socket.recv().unwrap());
+        assert_eq!(Some(Message::Binary(vec![0; 300])), socket.recv().unwrap());
+        assert_eq!(Some(Message::Close(Some((1000, "bye".to_string())))), socket.recv().unwrap());
+        assert_eq!(None, socket.recv().unwrap());
+        // the pong, then the close echoed back
+        let mut expected = vec![0x8A, 13];
+        expected.extend_from_slice(b"are you there");
+        expected.extend_from_slice(&[0x88, 2, 0x03, 0xE8]);
+        assert_eq!(expected, pipe.output);
+    }
+
+    #[test]
+    fn protocol_errors_close_the_connection() {
+        let mut unmasked = client_frame(true, 0x1, b"hi");
+        unmasked[1] &= 0x7F;
+        for (frame, code) in [
+            (unmasked, close_code::PROTOCOL_ERROR),
+            (client_frame(true, 0x0, b"orphan"), close_code::PROTOCOL_ERROR),
+            (client_frame(false, 0x9, b"fragmented ping"), close_code::PROTOCOL_ERROR),
+            (client_frame(true, 0x1, &[0xFF, 0xFE]), close_code::INVALID_DATA),
+        ] {
+            let mut pipe = pipe(&[frame]);
+            let error = WebSocket::new(&mut pipe).recv().unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, error.kind());
+            assert_eq!(code.to_be_bytes(), pipe.output[2..4], "{}", error);
+        }
+    }
+
+    #[test]
+    fn echo_over_a_real_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut router = Router::new();
+            router.websocket("/echo/:name", |request, socket| {
+                let name = request.param("name").unwrap().to_string();
+                while let Ok(Some(message)) = socket.recv() {
+                    if let Message::Text(text) = message {
+                        socket.send_text(&format!("{}: {}", name, text)).unwrap();
+                    }
+                }
+            });
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &router, &ConnectionOptions::default());
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /echo/bob HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        client.write_all(&client_frame(true, 0x1, b"hi")).unwrap();
+        let mut frame = [0; 9];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!([0x81, 7], frame[..2]);
+        assert_eq!(b"bob: hi", &frame[2..]);
+
+        client.write_all(&client_frame(true, 0x8, &[0x03, 0xE8])).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(vec![0x88, 2, 0x03, 0xE8], rest);
+    }
+
+    #[test]
+    fn rejects_plain_requests_and_bad_keys() {
+        let handler: WebSocketHandler = Arc::new(|_, _| {});
+        let mut request = Request { method: "GET".to_string(), path: "/".to_string(), ..Default::default() };
+        assert_eq!(426, upgrade(&request, Arc::clone(&handler)).status);
+        request.headers.insert("upgrade".to_string(), "websocket".to_string());
+        request.headers.insert("connection".to_string(), "keep-alive, Upgrade".to_string());
+        request.headers.insert("sec-websocket-version".to_string(), "13".to_string());
+        request.headers.insert("sec-websocket-key".to_string(), "c2hvcnQ=".to_string());
+        assert_eq!(400, upgrade(&request, Arc::clone(&handler)).status);
+        request.headers.insert("sec-websocket-key".to_string(), "dGhlIHNhbXBsZSBub25jZQ==".to_string());
+        assert_eq!(101, upgrade(&request, handler).status);
+    }
+}
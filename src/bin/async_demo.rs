@@ -1,4 +1,4 @@
-use futures::{executor::block_on, Future};
+use futures::executor::block_on;
 
 fn main() {
     let future = some_fun();
@@ -18,7 +18,7 @@ async fn dance() -> i32 {
     let fut = sing();
     futures::join!(fut, drum());
     println!("Finished dancing!");
-    return 2;
+    2
 }
 async fn some_fun() {
     println!("Hello world!");
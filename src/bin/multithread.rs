@@ -30,7 +30,7 @@ fn demo_unsafe_pointers() {
         unsafe_func();
         println!("Absolute value of -3 according to C: {}", abs(-3));
         COUNTER += 1;
-        println!("COUNTER = {}", COUNTER);
+        println!("COUNTER = {}", *std::ptr::addr_of!(COUNTER));
     }
 }
 
@@ -53,7 +53,7 @@ fn main() {
         let handle = std::thread::spawn(move || {
             println!("Inside thread #{}", i);
             // x is copied, so each thread has its own x which it increments to 31
-            x = x + 1;
+            x += 1;
             let mut z_num: MutexGuard<i32> = z_copy.lock().expect("Couldnt lock mutex successfully");
             *z_num += 1;
             println!("Done inside thread #{}, x = {}, z = {}", i, x, z_num);
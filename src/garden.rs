@@ -17,7 +17,7 @@ pub struct NewsArticle {
 
 pub trait Summary {
     fn summarize(&self) -> String {
-        return String::from("Default summary implementation");
+        String::from("Default summary implementation")
     }
 }
 
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::Read;
+
+use memmap2::Mmap;
+
+/// Files smaller than this are just read, setting up a mapping costs more than copying them
+pub const MMAP_THRESHOLD: u64 = 64 * 1024;
+
+/// Whether minigrep should memory map its input, picked with `--mmap`/`--no-mmap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MmapChoice {
+    /// Map regular files of at least [`MMAP_THRESHOLD`] bytes
+    #[default]
+    Auto,
+    /// Map every regular file, whatever the size
+    Always,
+    /// Always read into a heap buffer
+    Never,
+}
+
+/// The contents of an input file, either mapped into memory or read into a `String`.
+///
+/// Pipes, sockets and other special files cant be mapped, so they are always read.
+pub enum Input {
+    Mapped(Mmap),
+    Buffered(String),
+}
+
+impl Input {
+    pub fn open(path: &str, choice: MmapChoice) -> Result<Input, Box<dyn std::error::Error>> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let wants_mmap = metadata.is_file()
+            && metadata.len() > 0
+            && match choice {
+                MmapChoice::Auto => metadata.len() >= MMAP_THRESHOLD,
+                MmapChoice::Always => true,
+                MmapChoice::Never => false,
+            };
+
+        if wants_mmap {
+            // Safety: the mapping is only valid as long as nobody truncates or rewrites the
+            // file underneath us. Like other grep tools we accept that risk for the speed.
+            // If mapping fails for whatever reason, reading the file still works.
+            if let Ok(map) = unsafe { Mmap::map(&file) } {
+                // same error as read_to_string gives for a file that isnt utf-8
+                std::str::from_utf8(&map).map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+                })?;
+                return Ok(Input::Mapped(map));
+            }
+        }
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(Input::Buffered(contents))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            // Safety: checked to be valid utf-8 in open
+            Input::Mapped(map) => unsafe { std::str::from_utf8_unchecked(map) },
+            Input::Buffered(contents) => contents,
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Input::Mapped(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("minigrep-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn small_files_are_read_unless_forced() {
+        let path = write_temp_file("small", "safe, fast, productive.\n");
+        let auto = Input::open(&path, MmapChoice::Auto).unwrap();
+        assert!(!auto.is_mapped());
+        let always = Input::open(&path, MmapChoice::Always).unwrap();
+        assert!(always.is_mapped());
+        assert_eq!(auto.as_str(), always.as_str());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn large_files_are_mapped_unless_disabled() {
+        let path = write_temp_file("large", &"Pick three.\n".repeat(10_000));
+        assert!(Input::open(&path, MmapChoice::Auto).unwrap().is_mapped());
+        assert!(!Input::open(&path, MmapChoice::Never).unwrap().is_mapped());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_never_mapped() {
+        let input = Input::open("/dev/null", MmapChoice::Always).unwrap();
+        assert!(!input.is_mapped());
+        assert_eq!("", input.as_str());
+    }
+}
@@ -1,33 +1,116 @@
+pub mod input;
+pub mod literal;
+pub mod output;
+pub mod structured;
+pub mod tui;
+
+use input::{Input, MmapChoice};
+use output::SortBy;
+use structured::RecordFormat;
 
 pub trait Cow {
     // add code here
     fn moo(&self) -> String;
 }
 
+#[derive(Default)]
 pub struct Config {
     pub query: String,
-    pub file_path: String,
+    /// Files to search, each matching line is prefixed with its file when there is more than one
+    pub file_paths: Vec<String>,
     pub ignore_case: bool,
+    /// When set, the file is parsed as records and `query` is only matched against this column/field
+    pub field: Option<String>,
+    /// Record format for `field` searches, guessed from the file extension when not given
+    pub format: Option<RecordFormat>,
+    /// Whether to memory map the file instead of reading it into a buffer
+    pub mmap: MmapChoice,
+    /// Open the terminal UI instead of printing the matches
+    pub interactive: bool,
+    /// Print the line number and 1-based byte column of the first match before each line
+    pub column: bool,
+    /// Search the files in this order instead of the order they were given in
+    pub sort: Option<SortBy>,
+    /// Lines longer than this many characters are cut short
+    pub max_columns: Option<usize>,
 }
 
 impl Config {
+    /// Usage: `minigrep [--field NAME=QUERY] [--format csv|jsonl] [--mmap|--no-mmap] [--column]
+    /// [--sort path|modified|created] [--max-columns N] [QUERY] FILE...`
+    /// or `minigrep --interactive FILE...`
+    ///
+    /// The query is positional, unless it was already given as part of `--field` or is going
+    /// to be typed into the interactive mode.
     pub fn build(mut args: impl Iterator<Item=String>,) -> Result<Config, &'static str> {
         args.next();
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
-        };
+        let mut field = None;
+        let mut format = None;
+        let mut mmap = MmapChoice::Auto;
+        let mut interactive = false;
+        let mut column = false;
+        let mut sort = None;
+        let mut max_columns = None;
+        let mut positional = Vec::new();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--field" => {
+                    let spec = args.next().ok_or("--field expects NAME=QUERY")?;
+                    match spec.split_once('=') {
+                        Some((name, query)) if !name.is_empty() => {
+                            field = Some((name.to_string(), query.to_string()))
+                        },
+                        _ => return Err("--field expects NAME=QUERY"),
+                    }
+                },
+                "--format" => {
+                    let name = args.next().ok_or("--format expects csv or jsonl")?;
+                    format = Some(RecordFormat::parse(&name).ok_or("--format expects csv or jsonl")?);
+                },
+                "--mmap" => mmap = MmapChoice::Always,
+                "--no-mmap" => mmap = MmapChoice::Never,
+                "--interactive" | "-i" => interactive = true,
+                "--column" => column = true,
+                "--sort" => {
+                    let name = args.next().ok_or("--sort expects path, modified or created")?;
+                    sort = Some(SortBy::parse(&name).ok_or("--sort expects path, modified or created")?);
+                },
+                "--max-columns" => {
+                    let n = args.next().ok_or("--max-columns expects a number")?;
+                    max_columns = Some(n.parse().map_err(|_| "--max-columns expects a number")?);
+                },
+                _ => positional.push(arg),
+            }
+        }
+        let mut positional = positional.into_iter();
 
-        let file_path = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file path"),
+        let (field, query) = match field {
+            Some(_) if interactive => return Err("--interactive can't be combined with --field"),
+            Some((name, query)) => (Some(name), query),
+            None if interactive => (None, String::new()),
+            None => match positional.next() {
+                Some(arg) => (None, arg),
+                None => return Err("Didn't get a query string"),
+            },
         };
 
-        return Result::Ok(Config {
+        let file_paths: Vec<String> = positional.collect();
+        if file_paths.is_empty() {
+            return Err("Didn't get a file path");
+        }
+
+        Ok(Config {
             query,
-            file_path,
+            file_paths,
             ignore_case: std::env::var("IGNORE_CASE").is_ok(),
-        });
+            field,
+            format,
+            mmap,
+            interactive,
+            column,
+            sort,
+            max_columns,
+        })
     }
 }
 
@@ -38,31 +121,61 @@ impl Config {
 /// but will also be run as part of cargo test. Insane!
 ///
 /// ```rust
-/// let config = rust_scratch_pad::Config { query: "hello".to_string(), file_path: "./README.md".to_string(), ignore_case: true};
+/// let config = rust_scratch_pad::Config { query: "hello".to_string(), file_paths: vec!["./README.md".to_string()], ignore_case: true, ..Default::default() };
 /// assert!(rust_scratch_pad::run(config).is_ok());
 /// ```
-pub fn run(config: Config) -> Result<(), Box<dyn std::error::Error>> {
-    let contents = std::fs::read_to_string(config.file_path)?;
-    let lines : std::vec::Vec<&str> = if config.ignore_case {
-        search_case_insensitive(&config.query, &contents)
-    } else {
-        search(&config.query, &contents)
-    };
-    for line in lines {
-        println!("{}", line);
+pub fn run(mut config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(sort_by) = config.sort {
+        output::sort_paths(&mut config.file_paths, sort_by)?;
+    }
+    if config.interactive {
+        return tui::run(&config);
+    }
+
+    let searcher = literal::LiteralSearcher::new(&config.query, config.ignore_case);
+    for file_path in &config.file_paths {
+        let input = Input::open(file_path, config.mmap)?;
+        let contents = input.as_str();
+        let prefix = if config.file_paths.len() > 1 { format!("{}:", file_path) } else { String::new() };
+        let max_columns = config.max_columns.unwrap_or(usize::MAX);
+
+        if let Some(field) = &config.field {
+            let format = config.format
+                .or_else(|| RecordFormat::from_path(file_path))
+                .ok_or("Couldn't tell the record format from the file name, pass --format csv|jsonl")?;
+            let records = structured::search_records(format, field, &config.query, contents, config.ignore_case)?;
+            for record in records {
+                println!("{}{}", prefix, output::truncate_line(&record, max_columns));
+            }
+            continue;
+        }
+
+        if config.column {
+            for m in searcher.find_matches(contents) {
+                println!("{}{}:{}:{}", prefix, m.line_number, m.column, output::truncate_line(m.line, max_columns));
+            }
+            continue;
+        }
+
+        let lines : std::vec::Vec<&str> = searcher.find_lines(contents);
+        for line in lines {
+            println!("{}{}", prefix, output::truncate_line(line, max_columns));
+        }
     }
     Ok(())
 }
 
-fn search<'a>(query:&str, contents:&'a str) -> Vec<&'a str> {
-    return contents.lines().filter(|line| line.contains(&query)).collect();
+/// Straightforward line by line search, kept around as the baseline for the benchmarks.
+/// `run` uses the faster [`literal::LiteralSearcher`] instead.
+pub fn search<'a>(query:&str, contents:&'a str) -> Vec<&'a str> {
+    contents.lines().filter(|line| line.contains(query)).collect()
 }
 
-fn search_case_insensitive<'a>(query:&str, contents:&'a str) -> Vec<&'a str> {
-    return contents
+pub fn search_case_insensitive<'a>(query:&str, contents:&'a str) -> Vec<&'a str> {
+    contents
         .lines()
         .filter(|line| line.to_lowercase().contains(&query.to_lowercase()))
-        .collect();
+        .collect()
 }
 
 #[cfg(test)]
@@ -90,5 +203,37 @@ Pick three.
 rustling job";
         assert_eq!(vec!["Rust:", "rustling job"], search_case_insensitive(query, contents));
     }
-}
 
+    #[test]
+    fn build_field_config() {
+        let args = ["minigrep", "--field", "level=error", "logs.jsonl"].map(String::from);
+        let config = Config::build(args.into_iter()).unwrap();
+        assert_eq!(Some("level".to_string()), config.field);
+        assert_eq!("error", config.query);
+        assert_eq!(vec!["logs.jsonl".to_string()], config.file_paths);
+
+        let args = ["minigrep", "--field", "level", "logs.jsonl"].map(String::from);
+        assert!(Config::build(args.into_iter()).is_err());
+    }
+
+    #[test]
+    fn build_interactive_config() {
+        let args = ["minigrep", "--interactive", "a.txt", "b.txt"].map(String::from);
+        let config = Config::build(args.into_iter()).unwrap();
+        assert!(config.interactive);
+        assert_eq!("", config.query);
+        assert_eq!(vec!["a.txt".to_string(), "b.txt".to_string()], config.file_paths);
+    }
+
+    #[test]
+    fn build_output_config() {
+        let args = ["minigrep", "--column", "--sort", "path", "--max-columns", "80", "fn", "src"].map(String::from);
+        let config = Config::build(args.into_iter()).unwrap();
+        assert!(config.column);
+        assert_eq!(Some(SortBy::Path), config.sort);
+        assert_eq!(Some(80), config.max_columns);
+
+        let args = ["minigrep", "--max-columns", "lots", "fn", "src"].map(String::from);
+        assert!(Config::build(args.into_iter()).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use memchr::memmem;
+
+/// A matching line along with where it was found
+#[derive(Debug, PartialEq)]
+pub struct LineMatch<'a> {
+    /// 1-based line number
+    pub line_number: usize,
+    /// Byte offset of the start of the line in the searched contents
+    pub offset: usize,
+    /// 1-based byte column of the first match in the line
+    pub column: usize,
+    pub line: &'a str,
+}
+
+/// Finds the lines of a buffer which contain a literal query.
+///
+/// Instead of walking the buffer line by line, it scans the whole buffer for
+/// candidate positions with the SIMD accelerated routines from `memchr` and only
+/// looks for the surrounding line breaks once something was found. Since most
+/// lines usually dont match, most of the buffer is never split into lines at all.
+///
+/// When ignoring case with an ascii query, only ascii letters are case folded.
+pub struct LiteralSearcher {
+    needle: Vec<u8>,
+    ignore_case: bool,
+    // index into needle of the byte that is least likely to show up in text,
+    // this is the byte we look for first when ignoring case
+    rare_index: usize,
+}
+
+impl LiteralSearcher {
+    pub fn new(query: &str, ignore_case: bool) -> LiteralSearcher {
+        let needle: Vec<u8> = if ignore_case {
+            query.to_ascii_lowercase().into_bytes()
+        } else {
+            query.as_bytes().to_vec()
+        };
+        let rare_index = (0..needle.len())
+            .min_by_key(|&i| byte_frequency_rank(needle[i]))
+            .unwrap_or(0);
+        LiteralSearcher { needle, ignore_case, rare_index }
+    }
+
+    pub fn find_lines<'a>(&self, contents: &'a str) -> Vec<&'a str> {
+        if self.needle.is_empty() {
+            return contents.lines().collect();
+        }
+        // a line never contains a line break, so there is nothing to find
+        if self.needle.contains(&b'\n') {
+            return Vec::new();
+        }
+        if self.ignore_case && !self.needle.is_ascii() {
+            // ascii case folding is only correct for ascii, so do it the slow way
+            return search_lowercased(&String::from_utf8_lossy(&self.needle), contents);
+        }
+
+        let haystack = contents.as_bytes();
+        let finder = memmem::Finder::new(&self.needle);
+        let mut lines = Vec::new();
+        let mut position = 0;
+        while position < haystack.len() {
+            let found = if self.ignore_case {
+                self.find_ignoring_case(haystack, position)
+            } else {
+                finder.find(&haystack[position..]).map(|offset| position + offset)
+            };
+            let Some(found) = found else { break };
+
+            let line_start = memchr::memrchr(b'\n', &haystack[..found]).map_or(0, |i| i + 1);
+            let line_end = memchr::memchr(b'\n', &haystack[found..]).map_or(haystack.len(), |i| found + i);
+            // same as str::lines, which also drops the \r of a \r\n line ending
+            let line = &contents[line_start..line_end];
+            lines.push(line.strip_suffix('\r').unwrap_or(line));
+            // the rest of this line is already printed, skip straight to the next one
+            position = line_end + 1;
+        }
+        lines
+    }
+
+    /// Like [`LiteralSearcher::find_lines`], but also works out line numbers and columns,
+    /// which costs another pass over the contents up to the last match.
+    pub fn find_matches<'a>(&self, contents: &'a str) -> Vec<LineMatch<'a>> {
+        let bytes = contents.as_bytes();
+        let mut line_number = 1;
+        let mut counted_up_to = 0;
+        self.find_lines(contents)
+            .into_iter()
+            .map(|line| {
+                // the lines are slices of contents, so their offset falls out of the pointers
+                let offset = line.as_ptr() as usize - contents.as_ptr() as usize;
+                line_number += memchr::memchr_iter(b'\n', &bytes[counted_up_to..offset]).count();
+                counted_up_to = offset;
+                LineMatch { line_number, offset, column: self.first_match_in(line) + 1, line }
+            })
+            .collect()
+    }
+
+    // byte index of the first match in a line which is known to match
+    fn first_match_in(&self, line: &str) -> usize {
+        if self.needle.is_empty() {
+            0
+        } else if !self.ignore_case {
+            memmem::find(line.as_bytes(), &self.needle).unwrap_or(0)
+        } else if self.needle.is_ascii() {
+            self.find_ignoring_case(line.as_bytes(), 0).unwrap_or(0)
+        } else {
+            let query = String::from_utf8_lossy(&self.needle).to_lowercase();
+            line.char_indices()
+                .find(|&(i, _)| line[i..].to_lowercase().starts_with(&query))
+                .map_or(0, |(i, _)| i)
+        }
+    }
+
+    // prefilter on the rare byte in both cases, then verify the whole needle around it
+    fn find_ignoring_case(&self, haystack: &[u8], from: usize) -> Option<usize> {
+        let rare = self.needle[self.rare_index];
+        let mut position = from + self.rare_index;
+        while position < haystack.len() {
+            let candidate = position
+                + memchr::memchr2(rare, rare.to_ascii_uppercase(), &haystack[position..])?;
+            let start = candidate - self.rare_index;
+            let end = start + self.needle.len();
+            if end > haystack.len() {
+                return None;
+            }
+            if haystack[start..end].eq_ignore_ascii_case(&self.needle) {
+                return Some(start);
+            }
+            position = candidate + 1;
+        }
+        None
+    }
+}
+
+fn search_lowercased<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+    let query = query.to_lowercase();
+    contents
+        .lines()
+        .filter(|line| line.to_lowercase().contains(&query))
+        .collect()
+}
+
+// Rough ranking of how common a byte is in source code and english text, lower is more common.
+// Bytes not listed are assumed to be rare.
+fn byte_frequency_rank(byte: u8) -> usize {
+    const COMMON: &[u8] = b" etaoinsrhldcu\nmfpgwybv,.k_()=;\"-'x:/0{}1j2q*z<>[]3";
+    COMMON
+        .iter()
+        .position(|&common| common == byte.to_ascii_lowercase())
+        .unwrap_or(COMMON.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENTS: &str = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape\r
+rustling job";
+
+    #[test]
+    fn matches_str_contains() {
+        for query in ["duct", "Duct", "t", "tape", "e.\nP", "", "nothing"] {
+            let expected: Vec<&str> = CONTENTS.lines().filter(|line| line.contains(query)).collect();
+            assert_eq!(expected, LiteralSearcher::new(query, false).find_lines(CONTENTS), "query {:?}", query);
+        }
+    }
+
+    #[test]
+    fn matches_lowercased_contains() {
+        for query in ["rUsT", "DUCT TAPE", "pick", "b", "Straße"] {
+            assert_eq!(
+                search_lowercased(query, CONTENTS),
+                LiteralSearcher::new(query, true).find_lines(CONTENTS),
+                "query {:?}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn one_line_per_match() {
+        let contents = "aaaa\nbab\n\naa";
+        assert_eq!(vec!["aaaa", "bab", "aa"], LiteralSearcher::new("a", false).find_lines(contents));
+        assert_eq!(vec!["aaaa", "aa"], LiteralSearcher::new("AA", true).find_lines(contents));
+    }
+
+    #[test]
+    fn matches_known_line_and_column() {
+        let matches = LiteralSearcher::new("rust", true).find_matches(CONTENTS);
+        assert_eq!(
+            vec![
+                LineMatch { line_number: 1, offset: 0, column: 1, line: "Rust:" },
+                LineMatch { line_number: 5, offset: 53, column: 1, line: "rustling job" },
+            ],
+            matches
+        );
+        let matches = LiteralSearcher::new("t", false).find_matches(CONTENTS);
+        assert_eq!(vec![(1, 4), (2, 10), (3, 6), (4, 4), (5, 4)], matches.iter().map(|m| (m.line_number, m.column)).collect::<Vec<_>>());
+    }
+}
@@ -41,14 +41,16 @@ fn it_works() -> Result<(), String> {
 }
 
 
+#[allow(dead_code)]
 struct Pancake;
 
 impl Cow for Pancake {
     fn moo(&self) -> String {
-        return String::from("mooooo!!!");
+        String::from("mooooo!!!")
     }
 }
 
+#[allow(dead_code)]
 #[derive(Cow)]
 struct Pizza;
 
@@ -80,7 +82,7 @@ fn demo(s:&String) -> &String {
     // below should be a compile time error because didn not bring the trait into scope
     // println!("{}", _my_article.summarize());
 
-    return s;
+    s
     // let mut s = String::from("  hello  ");
     //
     // let r1 = &s;
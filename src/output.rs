@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::time::SystemTime;
+
+/// The order files are searched (and so printed) in, picked with `--sort`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    Path,
+    /// Oldest modification time first
+    Modified,
+    /// Oldest creation time first
+    Created,
+}
+
+impl SortBy {
+    pub fn parse(name: &str) -> Option<SortBy> {
+        match name {
+            "path" => Some(SortBy::Path),
+            "modified" => Some(SortBy::Modified),
+            "created" => Some(SortBy::Created),
+            _ => None,
+        }
+    }
+}
+
+/// Sorts the paths in place, ties between equal timestamps are broken by the path
+/// so the order is the same from one run to the next.
+pub fn sort_paths(paths: &mut [String], sort_by: SortBy) -> Result<(), Box<dyn Error>> {
+    if sort_by == SortBy::Path {
+        paths.sort();
+        return Ok(());
+    }
+
+    let mut keyed: Vec<(SystemTime, String)> = Vec::with_capacity(paths.len());
+    for path in paths.iter() {
+        let metadata = std::fs::metadata(path)?;
+        let time = match sort_by {
+            SortBy::Modified => metadata.modified(),
+            // not every platform/filesystem records this, in which case this is an error
+            _ => metadata.created(),
+        }
+        .map_err(|e| format!("{}: {}", path, e))?;
+        keyed.push((time, path.clone()));
+    }
+    keyed.sort();
+    for (slot, (_, path)) in paths.iter_mut().zip(keyed) {
+        *slot = path;
+    }
+    Ok(())
+}
+
+/// Cuts a line down to `max_columns` characters, saying how much was left out
+pub fn truncate_line(line: &str, max_columns: usize) -> std::borrow::Cow<'_, str> {
+    match line.char_indices().nth(max_columns) {
+        None => std::borrow::Cow::Borrowed(line),
+        Some((cut, _)) => {
+            let omitted = line[cut..].chars().count();
+            std::borrow::Cow::Owned(format!("{} [... {} more characters]", &line[..cut], omitted))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_lines_are_truncated_on_char_boundaries() {
+        assert_eq!("short", truncate_line("short", 5));
+        assert_eq!("héll [... 3 more characters]", truncate_line("héllo 😻", 4));
+    }
+
+    #[test]
+    fn sort_by_path() {
+        let mut paths = vec!["src/lib.rs".to_string(), "README.md".to_string(), "Cargo.toml".to_string()];
+        sort_paths(&mut paths, SortBy::Path).unwrap();
+        assert_eq!(vec!["Cargo.toml", "README.md", "src/lib.rs"], paths);
+    }
+
+    #[test]
+    fn sort_by_missing_file_is_an_error() {
+        let mut paths = vec!["does/not/exist".to_string()];
+        assert!(sort_paths(&mut paths, SortBy::Modified).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use std::error::Error;
+
+/// The kinds of structured input that `--field` searches understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// Comma separated values, the first record is the header naming the columns
+    Csv,
+    /// One JSON object per line (also known as ndjson)
+    JsonLines,
+}
+
+impl RecordFormat {
+    pub fn parse(name: &str) -> Option<RecordFormat> {
+        match name.to_lowercase().as_str() {
+            "csv" => Some(RecordFormat::Csv),
+            "jsonl" | "ndjson" | "json" => Some(RecordFormat::JsonLines),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the file extension, used when `--format` is not given
+    pub fn from_path(path: &str) -> Option<RecordFormat> {
+        let extension = std::path::Path::new(path).extension()?.to_str()?;
+        RecordFormat::parse(extension)
+    }
+}
+
+/// Returns the records whose `field` contains `query`.
+///
+/// CSV records are written back out as CSV (so quoting survives), JSON Lines
+/// records are returned exactly as they appeared in the input.
+pub fn search_records(
+    format: RecordFormat,
+    field: &str,
+    query: &str,
+    contents: &str,
+    ignore_case: bool,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    match format {
+        RecordFormat::Csv => search_csv(field, query, contents, ignore_case),
+        RecordFormat::JsonLines => search_json_lines(field, query, contents, ignore_case),
+    }
+}
+
+fn matches(value: &str, query: &str, ignore_case: bool) -> bool {
+    if ignore_case {
+        value.to_lowercase().contains(&query.to_lowercase())
+    } else {
+        value.contains(query)
+    }
+}
+
+fn search_csv(
+    field: &str,
+    query: &str,
+    contents: &str,
+    ignore_case: bool,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let column = reader
+        .headers()?
+        .iter()
+        .position(|header| header == field)
+        .ok_or_else(|| format!("No column named '{}' in the csv header", field))?;
+
+    let mut results = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        // a short record simply has nothing in that column, so it cant match
+        let Some(value) = record.get(column) else { continue };
+        if matches(value, query, ignore_case) {
+            let mut writer = csv::WriterBuilder::new()
+                .terminator(csv::Terminator::Any(b'\n'))
+                .from_writer(vec![]);
+            writer.write_record(&record)?;
+            let line = String::from_utf8(writer.into_inner()?)?;
+            results.push(line.trim_end_matches('\n').to_string());
+        }
+    }
+    Ok(results)
+}
+
+fn search_json_lines(
+    field: &str,
+    query: &str,
+    contents: &str,
+    ignore_case: bool,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    // dots in the field name walk into nested objects, e.g. `http.status`. Each key is escaped
+    // for the JSON pointer, `~` as `~0` and `/` as `~1`, the order matters.
+    let pointer: String = field
+        .split('.')
+        .map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+        .collect();
+    let mut results = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| format!("line {}: invalid json: {}", index + 1, e))?;
+        let value = match record.pointer(&pointer) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(serde_json::Value::Null) | None => continue,
+            Some(other) => other.to_string(),
+        };
+        if matches(&value, query, ignore_case) {
+            results.push(line.to_string());
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_matches_named_column() {
+        let contents = "\
+time,level,message
+1,info,started
+2,error,\"disk full, retrying\"
+3,info,error count is zero";
+        assert_eq!(
+            vec!["2,error,\"disk full, retrying\""],
+            search_records(RecordFormat::Csv, "level", "error", contents, false).unwrap()
+        );
+    }
+
+    #[test]
+    fn csv_unknown_column_is_an_error() {
+        let contents = "time,level\n1,info";
+        assert!(search_records(RecordFormat::Csv, "severity", "info", contents, false).is_err());
+    }
+
+    #[test]
+    fn json_lines_matches_nested_field() {
+        let contents = r#"{"level":"ERROR","http":{"status":500}}
+{"level":"info","http":{"status":200}}
+{"level":"error"}"#;
+        assert_eq!(
+            vec![r#"{"level":"ERROR","http":{"status":500}}"#, r#"{"level":"error"}"#],
+            search_records(RecordFormat::JsonLines, "level", "error", contents, true).unwrap()
+        );
+        assert_eq!(
+            vec![r#"{"level":"ERROR","http":{"status":500}}"#],
+            search_records(RecordFormat::JsonLines, "http.status", "500", contents, false).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_lines_keys_with_slashes_and_tildes() {
+        let contents = r#"{"path/to":{"~home":"x"}}
+{"path":{"to":{"~home":"x"}}}"#;
+        assert_eq!(
+            vec![r#"{"path/to":{"~home":"x"}}"#],
+            search_records(RecordFormat::JsonLines, "path/to.~home", "x", contents, false).unwrap()
+        );
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(Some(RecordFormat::Csv), RecordFormat::from_path("logs/app.CSV"));
+        assert_eq!(Some(RecordFormat::JsonLines), RecordFormat::from_path("app.jsonl"));
+        assert_eq!(None, RecordFormat::from_path("README.md"));
+    }
+}
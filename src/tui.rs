@@ -0,0 +1,245 @@
+//! `minigrep --interactive`: type a query and watch the hits update as you go.
+//!
+//! Up/Down/PageUp/PageDown move through the hits, Enter opens the selected hit in `$EDITOR`,
+//! Tab toggles ignoring case and Esc or Ctrl-C quits.
+use std::error::Error;
+use std::process::Command;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::input::Input;
+use crate::literal::LiteralSearcher;
+use crate::Config;
+
+/// Lines shown above and below the selected hit in the preview
+const CONTEXT: usize = 3;
+/// Stop collecting hits after this many, nobody scrolls through more than that
+const MAX_HITS: usize = 10_000;
+
+/// A matching line, as byte offsets into the contents of one of the files
+#[derive(Debug, PartialEq)]
+struct Hit {
+    file: usize,
+    line_number: usize,
+    start: usize,
+    end: usize,
+}
+
+struct App {
+    files: Vec<(String, Input)>,
+    query: String,
+    ignore_case: bool,
+    hits: Vec<Hit>,
+    list_state: ListState,
+}
+
+pub fn run(config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut files = Vec::with_capacity(config.file_paths.len());
+    for path in &config.file_paths {
+        files.push((path.clone(), Input::open(path, config.mmap)?));
+    }
+    let mut app = App {
+        files,
+        query: String::new(),
+        ignore_case: config.ignore_case,
+        hits: Vec::new(),
+        list_state: ListState::default(),
+    };
+
+    // init also installs a panic hook which puts the terminal back to normal
+    let mut terminal = ratatui::init();
+    let result = app.event_loop(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl App {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Box<dyn Error>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else { continue };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                KeyCode::Up => self.move_selection(-1),
+                KeyCode::Down => self.move_selection(1),
+                KeyCode::PageUp => self.move_selection(-10),
+                KeyCode::PageDown => self.move_selection(10),
+                KeyCode::Tab => {
+                    self.ignore_case = !self.ignore_case;
+                    self.refresh();
+                },
+                KeyCode::Backspace => {
+                    self.query.pop();
+                    self.refresh();
+                },
+                KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.query.push(c);
+                    self.refresh();
+                },
+                KeyCode::Enter => {
+                    if let Some(hit) = self.selected_hit() {
+                        // hand the terminal over to the editor and take it back afterwards
+                        let path = &self.files[hit.file].0;
+                        ratatui::restore();
+                        let status = editor_command(path, hit.line_number).status();
+                        *terminal = ratatui::init();
+                        status?;
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.hits.clear();
+        // an empty query matches every line, that is not a useful thing to show
+        if !self.query.is_empty() {
+            let searcher = LiteralSearcher::new(&self.query, self.ignore_case);
+            for (file, (_, input)) in self.files.iter().enumerate() {
+                self.hits.extend(find_hits(file, input.as_str(), &searcher));
+                if self.hits.len() >= MAX_HITS {
+                    self.hits.truncate(MAX_HITS);
+                    break;
+                }
+            }
+        }
+        self.list_state.select(if self.hits.is_empty() { None } else { Some(0) });
+    }
+
+    fn move_selection(&mut self, by: isize) {
+        if self.hits.is_empty() {
+            return;
+        }
+        let current = self.list_state.selected().unwrap_or(0) as isize;
+        let next = (current + by).clamp(0, self.hits.len() as isize - 1);
+        self.list_state.select(Some(next as usize));
+    }
+
+    fn selected_hit(&self) -> Option<&Hit> {
+        self.list_state.selected().and_then(|i| self.hits.get(i))
+    }
+
+    fn contents(&self, hit: &Hit) -> &str {
+        self.files[hit.file].1.as_str()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [query_area, hits_area, preview_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(3),
+            Constraint::Length(2 * CONTEXT as u16 + 3),
+        ])
+        .areas(frame.area());
+
+        let count = if self.hits.len() >= MAX_HITS { format!("first {}", MAX_HITS) } else { self.hits.len().to_string() };
+        let case = if self.ignore_case { "ignoring case" } else { "case sensitive" };
+        let title = format!(" Query ({} hits, {}, Tab to toggle) ", count, case);
+        frame.render_widget(Paragraph::new(self.query.as_str()).block(Block::bordered().title(title)), query_area);
+        frame.set_cursor_position((query_area.x + 1 + self.query.chars().count() as u16, query_area.y + 1));
+
+        // long lines (minified js...) would only be clipped anyway, so dont format all of them
+        let width = hits_area.width as usize;
+        let items: Vec<ListItem> = self
+            .hits
+            .iter()
+            .map(|hit| {
+                let text = &self.contents(hit)[hit.start..hit.end];
+                let label = format!("{}:{}: ", self.files[hit.file].0, hit.line_number);
+                let text: String = text.chars().take(width.saturating_sub(label.len())).collect();
+                ListItem::new(Line::from(vec![Span::from(label).dim(), Span::from(text)]))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(" Hits (Enter opens $EDITOR, Esc quits) "))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, hits_area, &mut self.list_state);
+
+        let preview: Vec<Line> = match self.selected_hit() {
+            Some(hit) => context_lines(self.contents(hit), hit, CONTEXT)
+                .into_iter()
+                .map(|(line_number, text)| {
+                    let line = Line::from(format!("{:>6} {}", line_number, text));
+                    if line_number == hit.line_number { line.bold() } else { line }
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        frame.render_widget(Paragraph::new(preview).block(Block::bordered().title(" Preview ")), preview_area);
+    }
+}
+
+fn find_hits(file: usize, contents: &str, searcher: &LiteralSearcher) -> Vec<Hit> {
+    searcher
+        .find_matches(contents)
+        .into_iter()
+        .map(|m| Hit { file, line_number: m.line_number, start: m.offset, end: m.offset + m.line.len() })
+        .collect()
+}
+
+/// The hit's line plus up to `context` lines either side, with their line numbers
+fn context_lines<'a>(contents: &'a str, hit: &Hit, context: usize) -> Vec<(usize, &'a str)> {
+    let bytes = contents.as_bytes();
+    let mut from = hit.start;
+    let mut before = 0;
+    while before < context && from > 0 {
+        // bytes[from - 1] is the line break ending the previous line
+        from = memchr::memrchr(b'\n', &bytes[..from - 1]).map_or(0, |i| i + 1);
+        before += 1;
+    }
+    contents[from..]
+        .lines()
+        .take(before + 1 + context)
+        .enumerate()
+        .map(|(i, line)| (hit.line_number - before + i, line))
+        .collect()
+}
+
+/// `$EDITOR +LINE PATH`, which vi, emacs, nano and friends all understand
+fn editor_command(path: &str, line_number: usize) -> Command {
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    // EDITOR is allowed to carry arguments, e.g. "emacs -nw"
+    let mut words = editor.split_whitespace();
+    let mut command = Command::new(words.next().unwrap_or("vi"));
+    command.args(words).arg(format!("+{}", line_number)).arg(path);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENTS: &str = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape
+rustling job";
+
+    #[test]
+    fn hits_know_their_line_numbers() {
+        let hits = find_hits(0, CONTENTS, &LiteralSearcher::new("rust", true));
+        assert_eq!(vec![1, 5], hits.iter().map(|hit| hit.line_number).collect::<Vec<_>>());
+        assert_eq!("rustling job", &CONTENTS[hits[1].start..hits[1].end]);
+    }
+
+    #[test]
+    fn context_is_cut_off_at_the_edges() {
+        let hits = find_hits(0, CONTENTS, &LiteralSearcher::new("Pick", false));
+        assert_eq!(
+            vec![(2, "safe, fast, productive."), (3, "Pick three."), (4, "Duct tape")],
+            context_lines(CONTENTS, &hits[0], 1)
+        );
+        let hits = find_hits(0, CONTENTS, &LiteralSearcher::new("Rust", false));
+        assert_eq!(vec![(1, "Rust:"), (2, "safe, fast, productive.")], context_lines(CONTENTS, &hits[0], 1));
+    }
+}